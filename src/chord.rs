@@ -0,0 +1,368 @@
+// The notes one input plays at the same time, and the ways of pairing up the notes of two chords.
+
+/// A set of MIDI notes with their velocities.
+#[derive(Clone, Copy)]
+pub struct Chord {
+    /// One bit per MIDI note.
+    pub notes: u128,
+    velocities: [f32; 128],
+    /// The absolute sample the first note arrived at.
+    pub onset: u64,
+    /// Notes that already got their NoteOff, one bit per MIDI note.
+    pub released: u128,
+    /// When the released notes got their NoteOff.
+    ends: [u64; 128],
+}
+
+impl Default for Chord {
+    fn default() -> Self {
+        Self {
+            notes: 0,
+            velocities: [0.0; 128],
+            onset: 0,
+            released: 0,
+            ends: [0; 128],
+        }
+    }
+}
+
+impl Chord {
+    pub fn add(&mut self, note: u8, velocity: f32, time: u64) {
+        if self.is_empty() {
+            self.onset = time;
+        }
+        self.notes |= 1u128 << note;
+        // A note struck again is held again, its earlier NoteOff no longer counts
+        self.released &= !(1u128 << note);
+        self.velocities[note as usize] = velocity;
+    }
+
+    /// Remove every note. The velocities and ends of notes that aren't part of the chord are never
+    /// read, so they are left as they are.
+    pub fn clear(&mut self) {
+        self.notes = 0;
+        self.released = 0;
+        self.onset = 0;
+    }
+
+    /// Remember the NoteOff of a note that is still part of the chord.
+    pub fn release(&mut self, note: u8, time: u64) {
+        self.released |= 1u128 << note;
+        self.ends[note as usize] = time;
+    }
+
+    /// The released notes and the time of their NoteOffs.
+    pub fn iter_released(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        Notes(self.released).map(|note| (note, self.ends[note as usize]))
+    }
+
+    pub fn contains(&self, note: u8) -> bool {
+        self.notes & (1u128 << note) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.count_ones() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes == 0
+    }
+
+    pub fn velocity(&self, note: u8) -> f32 {
+        self.velocities[note as usize]
+    }
+
+    /// A copy that lends its notes and velocities to a group starting at `onset`, none of its
+    /// notes released. With `held_only`, notes that already ended are left out.
+    pub fn stand_in(&self, held_only: bool, onset: u64) -> Self {
+        Self {
+            notes: if held_only { self.notes & !self.released } else { self.notes },
+            velocities: self.velocities,
+            onset,
+            ..Self::default()
+        }
+    }
+
+    /// The notes and their velocities, from lowest to highest.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, f32)> + '_ {
+        Notes(self.notes).map(|note| (note, self.velocities[note as usize]))
+    }
+
+    /// The mean pitch and velocity of all notes.
+    pub fn average(&self) -> (f32, f32) {
+        let len = self.len().max(1) as f32;
+        let (notes, velocities) = self
+            .iter()
+            .fold((0.0, 0.0), |(notes, velocities), (note, velocity)| {
+                (notes + note as f32, velocities + velocity)
+            });
+
+        (notes / len, velocities / len)
+    }
+}
+
+/// Iterates over the set bits of a `u128` note mask, from lowest to highest note.
+pub struct Notes(pub u128);
+
+impl Iterator for Notes {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.0 == 0 {
+            return None;
+        }
+        let note = self.0.trailing_zeros() as u8;
+        self.0 &= self.0 - 1;
+        Some(note)
+    }
+}
+
+/// Pair the notes of two chords by rank, lowest with lowest and so on. If one chord has fewer
+/// notes, its notes are spread evenly over the notes of the other, so the outer voices always meet
+/// and some inner notes get used twice.
+pub fn pair_by_rank(a: &Chord, b: &Chord, mut pair: impl FnMut((u8, f32), (u8, f32))) {
+    let (len_a, len_b) = (a.len(), b.len());
+    let pairs = len_a.max(len_b);
+
+    for i in 0..pairs {
+        let note_a = a.iter().nth(spread(i, pairs, len_a));
+        let note_b = b.iter().nth(spread(i, pairs, len_b));
+        if let (Some(note_a), Some(note_b)) = (note_a, note_b) {
+            pair(note_a, note_b);
+        }
+    }
+}
+
+/// Map index `i` out of `len` onto the closest index out of `target_len`, keeping both ends.
+fn spread(i: usize, len: usize, target_len: usize) -> usize {
+    if len <= 1 || target_len == 0 {
+        0
+    } else {
+        (i * (target_len - 1) + (len - 1) / 2) / (len - 1)
+    }
+}
+
+/// Chords with more notes than this are paired by rank instead of by voice leading.
+const MAX_ASSIGNMENT: usize = 32;
+
+/// Pair the notes of two chords so that the voices move as little as possible, summed over all
+/// pairs. Every note of the bigger chord gets exactly one partner and every note of the smaller
+/// chord is used at least once.
+pub fn pair_by_voice_leading(a: &Chord, b: &Chord, mut pair: impl FnMut((u8, f32), (u8, f32))) {
+    let (len_a, len_b) = (a.len(), b.len());
+    let len = len_a.max(len_b);
+    if len > MAX_ASSIGNMENT {
+        return pair_by_rank(a, b, pair);
+    }
+
+    // Rows are the notes of the bigger chord, columns the notes of the smaller one
+    let swapped = len_b > len_a;
+    let (big, small) = if swapped { (b, a) } else { (a, b) };
+    let mut rows = [(0, 0.0); MAX_ASSIGNMENT];
+    let mut columns = [(0, 0.0); MAX_ASSIGNMENT];
+    for (row, note) in rows.iter_mut().zip(big.iter()) {
+        *row = note;
+    }
+    for (column, note) in columns.iter_mut().zip(small.iter()) {
+        *column = note;
+    }
+    let len_small = small.len();
+    if len_small == 0 {
+        return;
+    }
+
+    // The total motion comes first, the squared motion only decides between equally short ways.
+    // That spreads the movement over the voices and keeps them from crossing.
+    let distance = |row: usize, column: usize| {
+        let distance = (rows[row].0 as i64 - columns[column].0 as i64).abs();
+        (distance << 20) + distance * distance
+    };
+    let nearest = |row: usize| {
+        (0..len_small)
+            .min_by_key(|&column| distance(row, column))
+            .unwrap_or(0)
+    };
+    // Surplus columns stand for "any note of the smaller chord", so rows left over after every
+    // note of the smaller chord is taken just move to their closest one
+    let cost = |row: usize, column: usize| {
+        if column < len_small {
+            distance(row, column)
+        } else {
+            distance(row, nearest(row))
+        }
+    };
+
+    let assignment = hungarian(len, cost);
+    for (row, &column) in assignment.iter().take(len).enumerate() {
+        let column = if column < len_small { column } else { nearest(row) };
+        if swapped {
+            pair(columns[column], rows[row]);
+        } else {
+            pair(rows[row], columns[column]);
+        }
+    }
+}
+
+/// Solve the assignment problem for a `len` by `len` cost matrix with the Hungarian algorithm.
+/// Returns the column assigned to each row.
+#[allow(clippy::needless_range_loop)]
+fn hungarian(len: usize, cost: impl Fn(usize, usize) -> i64) -> [usize; MAX_ASSIGNMENT] {
+    const INF: i64 = i64::MAX / 2;
+    // Potentials of rows and columns, all arrays are 1-based with 0 as a virtual column
+    let mut u = [0; MAX_ASSIGNMENT + 1];
+    let mut v = [0; MAX_ASSIGNMENT + 1];
+    // The row assigned to each column, and the previous column on the augmenting path
+    let mut matched = [0usize; MAX_ASSIGNMENT + 1];
+    let mut way = [0usize; MAX_ASSIGNMENT + 1];
+
+    for row in 1..=len {
+        matched[0] = row;
+        let mut column = 0;
+        let mut min_slack = [INF; MAX_ASSIGNMENT + 1];
+        let mut used = [false; MAX_ASSIGNMENT + 1];
+
+        loop {
+            used[column] = true;
+            let current_row = matched[column];
+            let mut delta = INF;
+            let mut next_column = 0;
+
+            for j in 1..=len {
+                if !used[j] {
+                    let slack = cost(current_row - 1, j - 1) - u[current_row] - v[j];
+                    if slack < min_slack[j] {
+                        min_slack[j] = slack;
+                        way[j] = column;
+                    }
+                    if min_slack[j] < delta {
+                        delta = min_slack[j];
+                        next_column = j;
+                    }
+                }
+            }
+
+            for j in 0..=len {
+                if used[j] {
+                    u[matched[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+
+            column = next_column;
+            if matched[column] == 0 {
+                break;
+            }
+        }
+
+        // Flip the augmenting path
+        while column != 0 {
+            let previous = way[column];
+            matched[column] = matched[previous];
+            column = previous;
+        }
+    }
+
+    let mut assignment = [0; MAX_ASSIGNMENT];
+    for column in 1..=len {
+        if matched[column] != 0 {
+            assignment[matched[column] - 1] = column - 1;
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+
+    fn chord(notes: &[u8]) -> Chord {
+        let mut chord = Chord::default();
+        for &note in notes {
+            chord.add(note, 1.0, 0);
+        }
+        chord
+    }
+
+    fn voice_leading(a: &[u8], b: &[u8]) -> Vec<(u8, u8)> {
+        let mut pairs = Vec::new();
+        pair_by_voice_leading(&chord(a), &chord(b), |(a, _), (b, _)| pairs.push((a, b)));
+        pairs.sort();
+        pairs
+    }
+
+    /// The cheapest total cost over every assignment, by trying all of them.
+    fn brute_force(len: usize, cost: &impl Fn(usize, usize) -> i64) -> i64 {
+        fn search(row: usize, len: usize, used: u32, cost: &impl Fn(usize, usize) -> i64) -> i64 {
+            if row == len {
+                return 0;
+            }
+            (0..len)
+                .filter(|column| used & (1 << column) == 0)
+                .map(|column| cost(row, column) + search(row + 1, len, used | 1 << column, cost))
+                .min()
+                .unwrap_or(0)
+        }
+        search(0, len, 0, cost)
+    }
+
+    #[test]
+    fn a_note_struck_again_is_held_again() {
+        let mut chord = chord(&[60, 64]);
+        chord.release(60, 10);
+        chord.add(60, 0.5, 20);
+        assert_eq!(chord.iter_released().count(), 0);
+        assert_eq!(chord.velocity(60), 0.5);
+    }
+
+    #[test]
+    fn hungarian_finds_the_cheapest_assignment() {
+        let mut rng = Rng::new(0);
+        for len in 1..=7 {
+            for _ in 0..20 {
+                let mut matrix = [[0i64; 7]; 7];
+                for cell in matrix.iter_mut().flatten() {
+                    *cell = (rng.next_u64() % 100) as i64;
+                }
+                let cost = |row: usize, column: usize| matrix[row][column];
+
+                let assignment = hungarian(len, cost);
+                let mut columns: Vec<usize> = assignment[..len].to_vec();
+                columns.sort();
+                assert_eq!(columns, (0..len).collect::<Vec<_>>());
+                let total: i64 = (0..len).map(|row| cost(row, assignment[row])).sum();
+                assert_eq!(total, brute_force(len, &cost));
+            }
+        }
+    }
+
+    #[test]
+    fn voice_leading_keeps_voices_from_crossing() {
+        // Crossing would move the voices just as far in total, but one of them a lot more
+        assert_eq!(voice_leading(&[60, 62], &[64, 66]), [(60, 64), (62, 66)]);
+        assert_eq!(voice_leading(&[60, 64, 67], &[59, 65, 67]), [(60, 59), (64, 65), (67, 67)]);
+    }
+
+    #[test]
+    fn voice_leading_uses_every_note_of_the_smaller_chord() {
+        // By rank, 64 would go to 60 and the whole chord would move further
+        assert_eq!(
+            voice_leading(&[60, 64, 67, 72], &[60, 61]),
+            [(60, 60), (64, 61), (67, 61), (72, 61)],
+        );
+        // The pairs keep their order when the second chord is the bigger one
+        assert_eq!(
+            voice_leading(&[60, 61], &[60, 64, 67, 72]),
+            [(60, 60), (61, 64), (61, 67), (61, 72)],
+        );
+        assert_eq!(voice_leading(&[60, 72], &[66]), [(60, 66), (72, 66)]);
+    }
+
+    #[test]
+    fn voice_leading_with_an_empty_chord_pairs_nothing() {
+        assert_eq!(voice_leading(&[60, 64], &[]), []);
+        assert_eq!(voice_leading(&[], &[60]), []);
+    }
+}
@@ -0,0 +1,209 @@
+// Crossfading MIDI controllers between A and B.
+use std::sync::Arc;
+use nih_plug::prelude::*;
+use crate::chord::Notes;
+use crate::sources::{Sources, MAX_SOURCES};
+
+/// The 7-bit resolution of MIDI CCs and pressure.
+const CC_STEPS: f32 = 127.0;
+/// The 14-bit resolution of MIDI pitch bend.
+const BEND_STEPS: f32 = 16383.0;
+
+/// A value that is interpolated between the sources.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Controller {
+    Cc(u8),
+    PitchBend,
+    Pressure,
+}
+
+/// The weighted mean of the values of the sources. Sources that haven't sent anything are left
+/// out, so as long as only one has, that value is used as it is.
+pub fn blend(values: impl Iterator<Item = (Option<f32>, f32)>) -> Option<f32> {
+    let (mut sum, mut weights, mut plain_sum, mut count) = (0.0, 0.0, 0.0, 0);
+    for (value, weight) in values {
+        if let Some(value) = value {
+            sum += value * weight;
+            weights += weight;
+            plain_sum += value;
+            count += 1;
+        }
+    }
+
+    if weights > 0.0 {
+        Some(sum / weights)
+    } else if count > 0 {
+        Some(plain_sum / count as f32)
+    } else {
+        None
+    }
+}
+
+/// The last value a controller had on every source, and the last interpolated value that was sent.
+#[derive(Clone, Copy, Default)]
+struct Blend {
+    values: [Option<f32>; MAX_SOURCES],
+    sent: Option<f32>,
+}
+
+impl Blend {
+    /// The interpolated value, if it changed since the last one was sent.
+    fn next(&mut self, sources: &Sources, steps: f32) -> Option<f32> {
+        let values = self.values.iter().zip(sources.weights).take(sources.count);
+        let value = blend(values.map(|(&value, weight)| (value, weight)))?;
+
+        // Don't repeat values the synth can't tell apart
+        if self.sent.is_some_and(|sent| (sent * steps).round() == (value * steps).round()) {
+            return None;
+        }
+        self.sent = Some(value);
+        Some(value)
+    }
+}
+
+/// An incoming controller that sets the interpolation amount instead of the parameter.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum ControlSource {
+    #[name = "None"]
+    None,
+    #[name = "CC"]
+    Cc,
+    #[name = "Channel Pressure"]
+    Pressure,
+    #[name = "Pitch Bend"]
+    PitchBend,
+    /// The velocity of every note on the control channel.
+    #[name = "Note Velocity"]
+    Velocity,
+}
+
+impl ControlSource {
+    /// `None` if `event` isn't the control on `channel`, otherwise the amount it sets, if any.
+    /// Only CC number `cc` counts. The NoteOffs of the velocity control don't set anything.
+    pub fn amount<S: SysExMessage>(
+        self,
+        event: &NoteEvent<S>,
+        channel: u8,
+        cc: u8,
+    ) -> Option<Option<f32>> {
+        if event.channel() != Some(channel) {
+            return None;
+        }
+        match (self, event) {
+            (ControlSource::Cc, NoteEvent::MidiCC { cc: number, value, .. }) if *number == cc => {
+                Some(Some(*value))
+            }
+            (ControlSource::Pressure, NoteEvent::MidiChannelPressure { pressure, .. }) => {
+                Some(Some(*pressure))
+            }
+            (ControlSource::PitchBend, NoteEvent::MidiPitchBend { value, .. }) => {
+                Some(Some(*value))
+            }
+            (ControlSource::Velocity, NoteEvent::NoteOn { velocity, .. }) => Some(Some(*velocity)),
+            (ControlSource::Velocity, NoteEvent::NoteOff { .. }) => Some(None),
+            _ => None,
+        }
+    }
+}
+
+pub struct ControllerMorph {
+    ccs: [Blend; 128],
+    pitch_bend: Blend,
+    pressure: Blend,
+    /// The last poly pressure of every note on every source.
+    poly_pressure: [[Option<f32>; 128]; MAX_SOURCES],
+}
+
+impl Default for ControllerMorph {
+    fn default() -> Self {
+        Self {
+            ccs: [Blend::default(); 128],
+            pitch_bend: Blend::default(),
+            pressure: Blend::default(),
+            poly_pressure: [[None; 128]; MAX_SOURCES],
+        }
+    }
+}
+
+impl ControllerMorph {
+    /// Take a value from one of the sources. Returns the interpolated value to send, if it
+    /// changed.
+    pub fn set(
+        &mut self,
+        controller: Controller,
+        source: usize,
+        value: f32,
+        sources: &Sources,
+    ) -> Option<f32> {
+        let (blend, steps) = match controller {
+            Controller::Cc(cc) => (&mut self.ccs[cc as usize], CC_STEPS),
+            Controller::PitchBend => (&mut self.pitch_bend, BEND_STEPS),
+            Controller::Pressure => (&mut self.pressure, CC_STEPS),
+        };
+        blend.values[source] = Some(value);
+        blend.next(sources, steps)
+    }
+
+    /// Call `send` for every interpolated value that changed, for example because the weights
+    /// moved. Only the CCs in `ccs` (one bit per controller number) are morphed.
+    pub fn update(&mut self, sources: &Sources, ccs: u128, mut send: impl FnMut(Controller, f32)) {
+        for (cc, blend) in self.ccs.iter_mut().enumerate() {
+            if ccs & (1u128 << cc) == 0 {
+                continue;
+            }
+            if let Some(value) = blend.next(sources, CC_STEPS) {
+                send(Controller::Cc(cc as u8), value);
+            }
+        }
+        if let Some(value) = self.pitch_bend.next(sources, BEND_STEPS) {
+            send(Controller::PitchBend, value);
+        }
+        if let Some(value) = self.pressure.next(sources, CC_STEPS) {
+            send(Controller::Pressure, value);
+        }
+    }
+
+    pub fn set_poly_pressure(&mut self, source: usize, note: u8, pressure: f32) {
+        self.poly_pressure[source][note as usize] = Some(pressure);
+    }
+
+    /// The mean poly pressure of some notes (one bit per MIDI note) of a source, ignoring notes
+    /// that didn't send any.
+    pub fn poly_pressure(&self, source: usize, notes: u128) -> Option<f32> {
+        let (sum, count) = Notes(notes)
+            .filter_map(|note| self.poly_pressure[source][note as usize])
+            .fold((0.0, 0), |(sum, count), pressure| (sum + pressure, count + 1));
+
+        (count > 0).then(|| sum / count as f32)
+    }
+}
+
+/// One controller number that is interpolated instead of passed through.
+#[derive(Params)]
+pub struct MorphCcParams {
+    #[id = "cc"]
+    pub cc: IntParam,
+}
+
+impl MorphCcParams {
+    /// The parameters of the slot at `index`, switched off.
+    pub fn new(index: usize) -> Self {
+        Self {
+            cc: IntParam::new(
+                format!("Morph CC {}", index + 1),
+                -1,
+                IntRange::Linear { min: -1, max: 127 },
+            )
+                .with_value_to_string(Arc::new(|value| {
+                    if value < 0 { String::from("Off") } else { value.to_string() }
+                }))
+                .with_string_to_value(Arc::new(|string| {
+                    if string.trim().eq_ignore_ascii_case("off") {
+                        Some(-1)
+                    } else {
+                        string.trim().parse().ok()
+                    }
+                })),
+        }
+    }
+}
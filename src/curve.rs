@@ -0,0 +1,111 @@
+// Curves that warp the interpolation amount before it is applied to an attribute.
+use nih_plug::prelude::*;
+
+/// How strongly the exponential and logarithmic curves bend.
+const BEND: f32 = 3.0;
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum CurveShape {
+    #[name = "Linear"]
+    Linear,
+    /// Slow at the start, fast at the end.
+    #[name = "Exponential"]
+    Exponential,
+    /// Fast at the start, slow at the end.
+    #[name = "Logarithmic"]
+    Logarithmic,
+    /// Slow at both ends, fast in the middle.
+    #[name = "S-Curve"]
+    SCurve,
+    /// Jumps between a number of fixed amounts.
+    #[name = "Stepped"]
+    Stepped,
+    /// Straight lines through the breakpoints.
+    #[name = "Breakpoints"]
+    Breakpoints,
+}
+
+/// The curve of one attribute.
+#[derive(Params)]
+pub struct CurveParams {
+    #[id = "shape"]
+    pub shape: EnumParam<CurveShape>,
+
+    #[id = "steps"]
+    pub steps: IntParam,
+
+    // Where the breakpoint curve is at a quarter, half and three quarters of the way
+    #[id = "point_1"]
+    pub point_1: FloatParam,
+
+    #[id = "point_2"]
+    pub point_2: FloatParam,
+
+    #[id = "point_3"]
+    pub point_3: FloatParam,
+}
+
+impl CurveParams {
+    /// The curve parameters of the attribute called `name`. Every curve starts out linear.
+    pub fn new(name: &str) -> Self {
+        let point = |index: usize| {
+            FloatParam::new(
+                format!("{name} Curve Point {index}"),
+                index as f32 / 4.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+        };
+        Self {
+            shape: EnumParam::new(format!("{name} Curve"), CurveShape::Linear),
+            steps: IntParam::new(
+                format!("{name} Curve Steps"),
+                4,
+                IntRange::Linear { min: 2, max: 24 },
+            ),
+            point_1: point(1),
+            point_2: point(2),
+            point_3: point(3),
+        }
+    }
+
+    /// The current curve.
+    pub fn curve(&self) -> Curve {
+        Curve {
+            shape: self.shape.value(),
+            steps: self.steps.value().max(2) as u32,
+            points: [self.point_1.value(), self.point_2.value(), self.point_3.value()],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Curve {
+    pub shape: CurveShape,
+    pub steps: u32,
+    /// The breakpoints at a quarter, half and three quarters of the way.
+    pub points: [f32; 3],
+}
+
+impl Curve {
+    /// Warp an amount between 0 and 1. The ends always stay where they are, except for the steps.
+    pub fn apply(&self, amount: f32) -> f32 {
+        let t = amount.clamp(0.0, 1.0);
+        match self.shape {
+            CurveShape::Linear => t,
+            CurveShape::Exponential => (t * BEND).exp_m1() / BEND.exp_m1(),
+            CurveShape::Logarithmic => (t * BEND.exp_m1()).ln_1p() / BEND,
+            CurveShape::SCurve => t * t * (3.0 - 2.0 * t),
+            CurveShape::Stepped => {
+                let steps = self.steps as f32;
+                (t * steps).floor().min(steps - 1.0) / (steps - 1.0)
+            }
+            CurveShape::Breakpoints => {
+                let points = [0.0, self.points[0], self.points[1], self.points[2], 1.0];
+                let position = t * 4.0;
+                let segment = (position.floor() as usize).min(3);
+                let fraction = position - segment as f32;
+                points[segment] + (points[segment + 1] - points[segment]) * fraction
+            }
+        }
+    }
+}
@@ -1,41 +1,110 @@
-use nih_plug::prelude::{Editor};
+use nih_plug::prelude::{Editor, IntParam};
 use vizia_plug::vizia::prelude::*;
 use vizia_plug::{create_vizia_editor, ViziaState, ViziaTheming};
 use std::sync::Arc;
 use std::sync::atomic::Ordering::SeqCst;
 use vizia_plug::vizia::style::FontWeightKeyword::Bold;
+use vizia_plug::widgets::ParamEvent;
 use crate::gui::param_slider_knob::{ParamSliderKnob};
+use crate::gui::xy_pad::XyPad;
+use crate::tuning::{description, Tuning};
 use crate::MidiInterpolatorParams;
 
 #[derive(Lens, Clone)]
 pub(crate) struct Data {
     pub(crate) params: Arc<MidiInterpolatorParams>,
-    pub(crate) channels: (usize, usize),
+    /// The Scala files typed into the editor.
+    pub(crate) scale_path: String,
+    pub(crate) mapping_path: String,
+    /// The name of the loaded tuning, or why a file couldn't be loaded.
+    pub(crate) tuning_status: String,
 }
 
 impl Model for Data {
-    fn event(&mut self, _: &mut EventContext, event: &mut Event) {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
         event.map(|my_event, _meta| match my_event {
-            AppEvent::SetChannelA(selector) => {
-                self.channels.0 = *selector;
-                self.params.channel_a.store(*selector, SeqCst)
+            AppEvent::SetChannelA(selector) => set_channel(cx, &self.params.channel_a, *selector),
+            AppEvent::SetChannelB(selector) => set_channel(cx, &self.params.channel_b, *selector),
+            AppEvent::LoadScale(path) => {
+                self.scale_path = path.clone();
+                self.load_scala(path, false);
             }
-            AppEvent::SetChannelB(selector) => {
-                self.channels.1 = *selector;
-                self.params.channel_b.store(*selector, SeqCst)
+            AppEvent::LoadMapping(path) => {
+                self.mapping_path = path.clone();
+                self.load_scala(path, true);
             }
         });
     }
 }
 
+impl Data {
+    /// Read a Scala scale or keyboard mapping into the parameters, if it makes a valid tuning
+    /// together with the other file. An empty path unloads the file.
+    fn load_scala(&mut self, path: &str, is_mapping: bool) {
+        let text = if path.trim().is_empty() {
+            String::new()
+        } else {
+            match std::fs::read_to_string(path.trim()) {
+                Ok(text) => text,
+                Err(error) => {
+                    self.tuning_status = format!("Can't open the file: {error}");
+                    return;
+                }
+            }
+        };
+
+        let (target, other) = if is_mapping {
+            (&self.params.scala_mapping, &self.params.scala_scale)
+        } else {
+            (&self.params.scala_scale, &self.params.scala_mapping)
+        };
+        let other = other.read().map(|other| other.clone()).unwrap_or_default();
+        let (scale, mapping) = if is_mapping { (&other, &text) } else { (&text, &other) };
+        if !scale.trim().is_empty() {
+            if let Err(error) = Tuning::parse(scale, mapping) {
+                self.tuning_status = error.to_string();
+                return;
+            }
+        }
+
+        if let Ok(mut target) = target.write() {
+            *target = text;
+        }
+        self.params.tuning_changed.store(true, SeqCst);
+        self.tuning_status = tuning_status(&self.params);
+    }
+}
+
+/// Set a channel parameter as one gesture, so the host records it for automation and undo.
+fn set_channel(cx: &mut EventContext, param: &IntParam, channel: usize) {
+    cx.emit(ParamEvent::BeginSetParameter(param).upcast());
+    cx.emit(ParamEvent::SetParameter(param, channel as i32).upcast());
+    cx.emit(ParamEvent::EndSetParameter(param).upcast());
+}
+
+/// What the editor shows about the loaded tuning.
+fn tuning_status(params: &MidiInterpolatorParams) -> String {
+    let scale = params.scala_scale.read().map(|scale| scale.clone()).unwrap_or_default();
+    if scale.trim().is_empty() {
+        String::from("12-TET")
+    } else {
+        match description(&scale) {
+            "" => String::from("Scala tuning"),
+            name => name.to_string(),
+        }
+    }
+}
+
 enum AppEvent {
     SetChannelA(usize),
     SetChannelB(usize),
+    LoadScale(String),
+    LoadMapping(String),
 }
 
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (300, 100))
+    ViziaState::new(|| (300, 340))
 }
 
 pub(crate) fn create(
@@ -46,7 +115,9 @@ pub(crate) fn create(
         
         Data {
             params: params.clone(),
-            channels: (params.channel_a.load(SeqCst), params.channel_b.load(SeqCst)),
+            scale_path: String::new(),
+            mapping_path: String::new(),
+            tuning_status: tuning_status(&params),
         }
             .build(cx);
 
@@ -71,6 +142,38 @@ pub(crate) fn create(
             })
                 .alignment(Alignment::Center);
 
+            // Pitch and velocity on their own, when they aren't linked
+            HStack::new(cx, |cx| {
+                ParamSliderKnob::new(cx, Data::params, |params| &params.pitch_morph)
+                    .width(Pixels(100.0))
+                    .height(Pixels(20.0));
+
+                ParamSliderKnob::new(cx, Data::params, |params| &params.velocity_morph)
+                    .width(Pixels(100.0))
+                    .height(Pixels(20.0));
+            })
+                .alignment(Alignment::Center);
+
+            // Morphs between the first four sources in the XY Pad layout
+            XyPad::new(cx, Data::params, |params| &params.pad_x, |params| &params.pad_y);
+
+            // Scala files are loaded by typing their path, an empty path goes back to 12-TET
+            HStack::new(cx, |cx| {
+                Textbox::new(cx, Data::scale_path)
+                    .placeholder(".scl file")
+                    .on_submit(|cx, path, _| cx.emit(AppEvent::LoadScale(path)))
+                    .width(Pixels(140.0));
+
+                Textbox::new(cx, Data::mapping_path)
+                    .placeholder(".kbm file")
+                    .on_submit(|cx, path, _| cx.emit(AppEvent::LoadMapping(path)))
+                    .width(Pixels(140.0));
+            })
+                .alignment(Alignment::Center)
+                .height(Pixels(30.0));
+
+            Label::new(cx, Data::tuning_status);
+
         })
             .alignment(Alignment::TopCenter);
     })
@@ -80,8 +183,11 @@ fn dropdown_channel_selector(cx: &mut Context, use_b: bool) {
     Dropdown::new(
         cx,
         move |cx| {
-                Binding::new(cx, Data::channels,move |cx, channels| {
-                    let channel = if use_b { channels.get(cx).1 } else {channels.get(cx).0 };
+                let channel = Data::params.map(move |params| {
+                    if use_b { params.channel_b.value() } else { params.channel_a.value() }
+                });
+                Binding::new(cx, channel, move |cx, channel| {
+                    let channel = channel.get(cx);
                     Label::new(cx, format!("Channel {}", channel ))
                         .alignment(Alignment::Center)
                         .on_press(move |cx| cx.emit(PopupEvent::Open));
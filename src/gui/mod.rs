@@ -1 +1,2 @@
-pub mod param_slider_knob;
\ No newline at end of file
+pub mod param_slider_knob;
+pub mod xy_pad;
\ No newline at end of file
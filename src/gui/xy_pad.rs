@@ -0,0 +1,148 @@
+// A pad that moves two of NIH-plug's [`Param`]s at once, one along each axis. Built the same way
+// as `ParamSliderKnob`.
+use nih_plug::prelude::Param;
+use vizia_plug::vizia::prelude::*;
+use vizia_plug::widgets::param_base::ParamWidgetBase;
+use vizia_plug::widgets::util::{self, ModifiersExt};
+
+/// The size of the dot that shows the current position, in pixels.
+const DOT_SIZE: f32 = 10.0;
+
+pub struct XyPad {
+    x: ParamWidgetBase,
+    y: ParamWidgetBase,
+
+    /// Will be set to `true` if we're dragging the pad. Resetting the parameters should not
+    /// initiate a drag.
+    drag_active: bool,
+}
+
+impl XyPad {
+    /// Creates a new [`XyPad`] for two parameters, one for the horizontal and one for the vertical
+    /// axis. Works like [`ParamSliderKnob::new()`][super::param_slider_knob::ParamSliderKnob::new()]
+    /// with a projection function for each axis.
+    pub fn new<L, Params, PX, PY, FMapX, FMapY>(
+        cx: &mut Context,
+        params: L,
+        params_to_x: FMapX,
+        params_to_y: FMapY,
+    ) -> Handle<Self>
+    where
+        L: Lens<Target = Params> + Clone,
+        Params: 'static,
+        PX: Param + 'static,
+        PY: Param + 'static,
+        FMapX: Fn(&Params) -> &PX + Copy + 'static,
+        FMapY: Fn(&Params) -> &PY + Copy + 'static,
+    {
+        // Can't use `.to_string()` here as that would include the modulation.
+        let x_lens = ParamWidgetBase::make_lens(params.clone(), params_to_x, |param| {
+            param.unmodulated_normalized_value()
+        });
+        let y_lens = ParamWidgetBase::make_lens(params.clone(), params_to_y, |param| {
+            param.unmodulated_normalized_value()
+        });
+
+        Self {
+            x: ParamWidgetBase::new(cx, params.clone(), params_to_x),
+            y: ParamWidgetBase::new(cx, params, params_to_y),
+
+            drag_active: false,
+        }
+            .build(cx, move |cx| {
+                VStack::new(cx, |cx| {
+                    HStack::new(cx, |cx| {
+                        Element::new(cx)
+                            .background_color(RGBA::rgba(172, 53, 53, 255))
+                            .width(Pixels(DOT_SIZE))
+                            .height(Pixels(DOT_SIZE))
+                            .corner_radius(Percentage(50.0))
+                            // Hovering is handled on the pad as a whole, this should not affect
+                            // that
+                            .hoverable(false);
+                    })
+                        .padding_left(x_lens.map(|x| Percentage(x * 100.0)))
+                        .alignment(Alignment::TopLeft);
+                })
+                    .padding_top(y_lens.map(|y| Percentage((1.0 - y) * 100.0)))
+                    // Leave room for the dot, so it stays inside at the right and bottom edges
+                    .padding_right(Pixels(DOT_SIZE))
+                    .padding_bottom(Pixels(DOT_SIZE))
+                    .hoverable(false);
+            })
+            .border_color(Color::black())
+            .border_width(Pixels(2.0))
+            .width(Pixels(120.0))
+            .height(Pixels(120.0))
+    }
+
+    /// Move both parameters to the mouse position. This still needs to be wrapped in a parameter
+    /// automation gesture.
+    fn set_position_drag(&self, cx: &mut EventContext, x: f32, y: f32) {
+        self.x.set_normalized_value(cx, util::remap_current_entity_x_coordinate(cx, x));
+        self.y.set_normalized_value(cx, 1.0 - util::remap_current_entity_y_coordinate(cx, y));
+    }
+
+    /// Reset both parameters to their defaults.
+    fn reset(&self, cx: &mut EventContext) {
+        for param_base in [&self.x, &self.y] {
+            param_base.begin_set_parameter(cx);
+            param_base.set_normalized_value(cx, param_base.default_normalized_value());
+            param_base.end_set_parameter(cx);
+        }
+    }
+}
+
+impl View for XyPad {
+    fn element(&self) -> Option<&'static str> {
+        Some("xy-pad")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            WindowEvent::MouseDown(MouseButton::Left)
+            | WindowEvent::MouseTripleClick(MouseButton::Left) => {
+                if cx.modifiers().command() {
+                    // Ctrl+Click resets the pad instead of initiating a drag operation
+                    self.reset(cx);
+                } else {
+                    self.drag_active = true;
+                    cx.capture();
+                    cx.set_active(true);
+
+                    self.x.begin_set_parameter(cx);
+                    self.y.begin_set_parameter(cx);
+                    self.set_position_drag(cx, cx.mouse().cursor_x, cx.mouse().cursor_y);
+                }
+
+                meta.consume();
+            }
+            WindowEvent::MouseDoubleClick(MouseButton::Left)
+            | WindowEvent::MouseDown(MouseButton::Right)
+            | WindowEvent::MouseDoubleClick(MouseButton::Right)
+            | WindowEvent::MouseTripleClick(MouseButton::Right) => {
+                self.reset(cx);
+
+                meta.consume();
+            }
+            WindowEvent::MouseUp(MouseButton::Left) => {
+                if self.drag_active {
+                    self.drag_active = false;
+                    cx.release();
+                    cx.set_active(false);
+
+                    self.x.end_set_parameter(cx);
+                    self.y.end_set_parameter(cx);
+
+                    meta.consume();
+                }
+            }
+            WindowEvent::MouseMove(x, y) => {
+                if self.drag_active {
+                    self.set_position_drag(cx, *x, *y);
+                }
+            }
+            _ => {}
+        });
+    }
+}
@@ -1,14 +1,38 @@
 use std::collections::HashMap;
 use std::ops::Rem;
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use nih_plug::prelude::*;
 use vizia_plug::ViziaState;
 use nih_plug::prelude::SmoothingStyle::Linear;
+use crate::chord::{pair_by_rank, pair_by_voice_leading, Chord, Notes};
+use crate::controllers::{blend, ControlSource, Controller, ControllerMorph, MorphCcParams};
+use crate::curve::CurveParams;
+use crate::output::{BendSettings, OtherChannels, OutputMode};
+use crate::pitch::{blend_pitch, PitchSpace};
+use crate::random::Rng;
+use crate::scale::{Quantizer, Root, Rounding, Scale, ScaleDegreeParams};
+use crate::schedule::Scheduler;
+use crate::sources::{MissingPartner, SourceLayout, SourceParams, Sources, MAX_SOURCES};
+use crate::sysex::SysEx;
+use crate::tuning::Tuning;
+use crate::voices::VoiceTable;
 
+mod chord;
+mod controllers;
+mod curve;
 mod editor;
 mod gui;
+mod output;
+mod pitch;
+mod random;
+mod scale;
+mod schedule;
+mod sources;
+mod sysex;
+mod tuning;
+mod voices;
 
 #[derive(Params)]
 pub struct MidiInterpolatorParams {
@@ -17,14 +41,147 @@ pub struct MidiInterpolatorParams {
     #[persist = "editor-state"]
     pub editor_state: Arc<ViziaState>,
 
+    /// The text of the Scala scale file the notes are snapped to, empty for 12-TET.
+    #[persist = "scala-scale"]
+    pub scala_scale: RwLock<String>,
+
+    /// The text of the Scala keyboard mapping, empty for a linear mapping.
+    #[persist = "scala-mapping"]
+    pub scala_mapping: RwLock<String>,
+
+    /// Set when a Scala file was loaded, so the audio thread reads them again.
+    pub tuning_changed: Arc<AtomicBool>,
+
+    /// Passes every event on unchanged, after stopping the notes that are sounding.
+    #[id = "bypass"]
+    pub bypass: BoolParam,
+
     // Interpolate between A and B
     #[id = "interpolate_a_b"]
     pub interpolate_a_b: FloatParam,
 
-    pub channel_a: Arc<AtomicUsize>,
+    /// An incoming controller that sets the interpolation instead, until it is switched off.
+    #[id = "control_source"]
+    pub control_source: EnumParam<ControlSource>,
+
+    #[id = "control_channel"]
+    pub control_channel: IntParam,
+
+    #[id = "control_cc"]
+    pub control_cc: IntParam,
+
+    /// Whether A and B are morphed between, or the whole source list.
+    #[id = "layout"]
+    pub layout: EnumParam<SourceLayout>,
+
+    /// The channels of the vector layout and their weights.
+    #[nested(array, group = "Sources")]
+    pub sources: [SourceParams; MAX_SOURCES],
+
+    // Separate amounts for pitch and velocity in the A/B layout, unless they are linked to the
+    // main interpolation
+    #[id = "pitch_morph"]
+    pub pitch_morph: FloatParam,
+
+    #[id = "velocity_morph"]
+    pub velocity_morph: FloatParam,
+
+    #[id = "link_morph"]
+    pub link_morph: BoolParam,
+
+    /// Warps the pitch amount in the A/B layout.
+    #[nested(id_prefix = "pitch", group = "Pitch Curve")]
+    pub pitch_curve: CurveParams,
+
+    /// Warps the velocity amount in the A/B layout.
+    #[nested(id_prefix = "velocity", group = "Velocity Curve")]
+    pub velocity_curve: CurveParams,
+
+    // Where the XY pad is, between the first four sources
+    #[id = "pad_x"]
+    pub pad_x: FloatParam,
+
+    #[id = "pad_y"]
+    pub pad_y: FloatParam,
+
+    #[id = "mode"]
+    pub mode: EnumParam<InterpolationMode>,
+
+    /// What a group does with the sources that didn't play.
+    #[id = "missing_partner"]
+    pub missing_partner: EnumParam<MissingPartner>,
+
+    /// How long a chord can stand in for a source that didn't play, counted from its start.
+    #[id = "hold_timeout"]
+    pub hold_timeout: FloatParam,
+
+    /// Where the random choices of the probabilistic mode start, the same seed gives the same
+    /// choices every time the plugin is reset.
+    #[id = "seed"]
+    pub seed: IntParam,
+
+    /// Delays everything, so notes of different sources that are close together can be paired.
+    /// Lengths are only interpolated within this delay: once a source lets go, an interpolated
+    /// note only follows the sources that are still held for as long as the delay allows. With no
+    /// lookahead it ends exactly where the first source let go.
+    #[id = "lookahead"]
+    pub lookahead: FloatParam,
+
+    /// Notes of one source that start this close to each other are played as one chord.
+    #[id = "coincidence"]
+    pub coincidence: FloatParam,
+
+    // Snap the interpolated notes to a scale
+    #[id = "root"]
+    pub root: EnumParam<Root>,
+
+    #[id = "scale"]
+    pub scale: EnumParam<Scale>,
+
+    #[id = "rounding"]
+    pub rounding: EnumParam<Rounding>,
+
+    /// How the pitches of the sources are blended before they are snapped to the scale.
+    #[id = "pitch_space"]
+    pub pitch_space: EnumParam<PitchSpace>,
+
+    /// The frequency of A4 on the synth, so a Scala tuning lands on the right frequencies.
+    #[id = "reference_pitch"]
+    pub reference_pitch: FloatParam,
+
+    /// The degrees of the custom scale, from the root upwards.
+    #[nested(array, group = "Custom Scale")]
+    pub custom_scale: [ScaleDegreeParams; 12],
+
+    // How the interpolated pitches are sent
+    #[id = "output_mode"]
+    pub output_mode: EnumParam<OutputMode>,
+
+    #[id = "output_channel"]
+    pub output_channel: IntParam,
+
+    /// What happens to events on channels that aren't a source.
+    #[id = "other_channels"]
+    pub other_channels: EnumParam<OtherChannels>,
+
+    #[id = "bend_range"]
+    pub bend_range: IntParam,
+
+    #[id = "mpe_first_channel"]
+    pub mpe_first_channel: IntParam,
 
-    pub channel_b: Arc<AtomicUsize>,
-    //pub channel_b: EnumParam<MidiChannel>,
+    #[id = "mpe_channels"]
+    pub mpe_channels: IntParam,
+
+    /// Controllers that are crossfaded between A and B instead of passed through.
+    #[nested(array, group = "Morph CC")]
+    pub morph_ccs: [MorphCcParams; 4],
+
+    #[id = "channel_a"]
+    pub channel_a: IntParam,
+
+    #[id = "channel_b"]
+    pub channel_b: IntParam,
 }
 
 impl Default for MidiInterpolatorParams {
@@ -32,6 +189,14 @@ impl Default for MidiInterpolatorParams {
         Self {
             editor_state: editor::default_state(),
 
+            scala_scale: RwLock::new(String::new()),
+
+            scala_mapping: RwLock::new(String::new()),
+
+            tuning_changed: Arc::new(AtomicBool::new(false)),
+
+            bypass: BoolParam::new("Bypass", false).make_bypass(),
+
             interpolate_a_b: FloatParam::new(
                 "Interpolate between Input 1 and 2",
                 0.5,
@@ -39,16 +204,160 @@ impl Default for MidiInterpolatorParams {
             )
                 .with_smoother(Linear(50.0)),
 
-            //channel_a: EnumParam::new("Channel A", MidiChannel::Channel1),
-            channel_a: Arc::new(AtomicUsize::new(1)),
+            control_source: EnumParam::new("Morph Control", ControlSource::None),
+
+            control_channel: IntParam::new(
+                "Morph Control Channel",
+                16,
+                IntRange::Linear { min: 1, max: 16 },
+            ),
+
+            // The mod wheel
+            control_cc: IntParam::new("Morph Control CC", 1, IntRange::Linear { min: 0, max: 127 }),
+
+            layout: EnumParam::new("Sources", SourceLayout::AB),
+
+            sources: std::array::from_fn(SourceParams::new),
+
+            pitch_morph: FloatParam::new(
+                "Pitch Morph",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+                .with_smoother(Linear(50.0)),
+
+            velocity_morph: FloatParam::new(
+                "Velocity Morph",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+                .with_smoother(Linear(50.0)),
+
+            link_morph: BoolParam::new("Link Pitch and Velocity", true),
+
+            pitch_curve: CurveParams::new("Pitch"),
+
+            velocity_curve: CurveParams::new("Velocity"),
+
+            pad_x: FloatParam::new("X", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(Linear(50.0)),
+
+            pad_y: FloatParam::new("Y", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(Linear(50.0)),
+
+            mode: EnumParam::new("Mode", InterpolationMode::Average),
+
+            missing_partner: EnumParam::new("Missing Partner", MissingPartner::Pass),
+
+            hold_timeout: FloatParam::new(
+                "Hold Timeout",
+                2000.0,
+                FloatRange::Linear { min: 0.0, max: 10000.0 },
+            )
+                .with_unit(" ms")
+                .with_step_size(1.0),
+
+            seed: IntParam::new("Seed", 0, IntRange::Linear { min: 0, max: 9999 }),
+
+            lookahead: FloatParam::new(
+                "Lookahead",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 500.0 },
+            )
+                .with_unit(" ms")
+                .with_step_size(1.0),
+
+            coincidence: FloatParam::new(
+                "Coincidence Window",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 100.0 },
+            )
+                .with_unit(" ms")
+                .with_step_size(1.0),
+
+            root: EnumParam::new("Root", Root::C),
+
+            scale: EnumParam::new("Scale", Scale::Chromatic),
+
+            rounding: EnumParam::new("Rounding", Rounding::Nearest),
+
+            pitch_space: EnumParam::new("Pitch Space", PitchSpace::Linear),
+
+            reference_pitch: FloatParam::new(
+                "Reference Pitch",
+                440.0,
+                FloatRange::Linear { min: 400.0, max: 480.0 },
+            )
+                .with_unit(" Hz")
+                .with_step_size(0.1),
+
+            custom_scale: std::array::from_fn(ScaleDegreeParams::new),
+
+            output_mode: EnumParam::new("Output", OutputMode::Semitones),
+
+            output_channel: IntParam::new(
+                "Output Channel",
+                1,
+                IntRange::Linear { min: 1, max: 16 },
+            ),
+
+            other_channels: EnumParam::new("Other Channels", OtherChannels::Pass),
+
+            bend_range: IntParam::new("Pitch Bend Range", 48, IntRange::Linear { min: 1, max: 96 })
+                .with_unit(" st"),
 
-            channel_b: Arc::new(AtomicUsize::new(2)),
+            mpe_first_channel: IntParam::new(
+                "First MPE Channel",
+                2,
+                IntRange::Linear { min: 1, max: 16 },
+            ),
+
+            mpe_channels: IntParam::new("MPE Channels", 15, IntRange::Linear { min: 1, max: 16 }),
+
+            morph_ccs: std::array::from_fn(MorphCcParams::new),
+
+            channel_a: IntParam::new("Channel A", 1, IntRange::Linear { min: 1, max: 16 }),
+
+            channel_b: IntParam::new("Channel B", 2, IntRange::Linear { min: 1, max: 16 }),
         }
     }
 }
 
 struct MidiInterpolator {
     params: Arc<MidiInterpolatorParams>,
+    /// Remembers which interpolated notes are sounding, across process calls. The big tables are
+    /// boxed, so the plugin itself stays small enough to be moved around on the stack.
+    voices: Box<VoiceTable>,
+    /// The pitch bend range the synth was last told about, if any.
+    announced_bend: Option<BendSettings>,
+    /// Notes that are waiting for their partners, across process calls.
+    note_average: Box<NoteAverage>,
+    /// Everything that is sent, in time order.
+    scheduler: Scheduler,
+    /// The last controller values of A and B.
+    controllers: Box<ControllerMorph>,
+    /// Picks between A and B in the probabilistic mode.
+    rng: Rng,
+    /// The seed `rng` was started with.
+    seed: i32,
+    /// The Scala tuning from the parameters, if one is loaded.
+    tuning: Option<Tuning>,
+    /// The absolute sample the current block starts at.
+    position: u64,
+    sample_rate: f32,
+    /// The latency currently reported to the host, in samples.
+    latency: u32,
+    /// The interpolation amount set by the morph control.
+    control: Smoother<f32>,
+    /// Whether the morph control has set the amount, so it is used instead of the parameter.
+    controlled: bool,
+    /// How many notes were sent and not stopped yet, for every channel and note.
+    sent_notes: [[u8; 128]; 16],
+    /// Whether the transport was playing in the last block.
+    playing: bool,
+    bypassed: bool,
+    /// The source channels, their count and the output channel in the last block.
+    routing: ([u8; MAX_SOURCES], usize, u8),
 }
 
 impl Default for MidiInterpolator {
@@ -56,70 +365,736 @@ impl Default for MidiInterpolator {
         let default_params = Arc::new(MidiInterpolatorParams::default());
         Self {
             params: default_params.clone(),
+            voices: Box::default(),
+            announced_bend: None,
+            note_average: Box::default(),
+            scheduler: Scheduler::default(),
+            controllers: Box::default(),
+            rng: Rng::new(0),
+            seed: 0,
+            tuning: None,
+            position: 0,
+            sample_rate: 44100.0,
+            latency: 0,
+            control: Smoother::new(Linear(50.0)),
+            controlled: false,
+            sent_notes: [[0; 128]; 16],
+            playing: false,
+            bypassed: false,
+            routing: ([0; MAX_SOURCES], 0, 0),
+        }
+    }
+}
+
+/// How the notes that A and B play at the same time are turned into interpolated notes.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum InterpolationMode {
+    /// Average each chord and interpolate between the two averages, playing a single note.
+    #[name = "Average"]
+    Average,
+    /// Pair the notes of both chords by pitch rank and interpolate every pair on its own.
+    #[name = "Polyphonic"]
+    Polyphonic,
+    /// Pair the notes of both chords so the voices move as few semitones as possible.
+    #[name = "Voice Leading"]
+    VoiceLeading,
+    /// Play either the chord of A or the one of B as it is, B being picked with a probability of
+    /// the interpolation amount. If the picked source didn't play, the group stays silent, so of
+    /// the missing partner policies only `Drop` applies.
+    #[name = "Probabilistic"]
+    Probabilistic,
+}
+
+/// The parameter values a block of notes is interpolated with.
+struct Settings {
+    mode: InterpolationMode,
+    /// The channels that are morphed between and their smoothed weights at the current event.
+    sources: Sources,
+    quantizer: Quantizer,
+    pitch_space: PitchSpace,
+    output: OutputMode,
+    /// Where interpolated notes and controllers go, unless MPE spreads them over its own channels.
+    output_channel: u8,
+    other_channels: OtherChannels,
+    bend: BendSettings,
+    /// How long after the first note of a group the other sources can join it, in samples.
+    lookahead: u64,
+    /// How long after the first note of a chord more notes of the same source can join it, in
+    /// samples.
+    coincidence: u64,
+    /// Everything is delayed by this much, so a group is complete before it is played.
+    latency: u64,
+    missing_partner: MissingPartner,
+    /// In samples.
+    hold_timeout: u64,
+    /// The controllers that are morphed, one bit per CC number.
+    morph_ccs: u128,
+}
+
+impl Settings {
+    /// The channel morphed controllers are sent on. With MPE that is the manager channel below the
+    /// pool, so they reach every note.
+    fn control_channel(&self) -> u8 {
+        if self.output == OutputMode::PitchBend && self.bend.first_channel > 0 {
+            self.bend.first_channel - 1
+        } else {
+            self.output_channel
+        }
+    }
+}
+
+/// A NoteOff for an interpolated note, the scheduler sets its timing.
+fn note_off(
+    channel: u8,
+    note: u8,
+    voice_id: Option<i32>,
+    velocity: f32,
+) -> PluginNoteEvent<MidiInterpolator> {
+    NoteEvent::NoteOff {
+        timing: 0,
+        voice_id,
+        channel,
+        note,
+        velocity,
+    }
+}
+
+/// The same event on another channel. Events without a channel stay as they are.
+fn set_channel(
+    mut event: PluginNoteEvent<MidiInterpolator>,
+    new_channel: u8,
+) -> PluginNoteEvent<MidiInterpolator> {
+    match &mut event {
+        NoteEvent::NoteOn { channel, .. }
+        | NoteEvent::NoteOff { channel, .. }
+        | NoteEvent::Choke { channel, .. }
+        | NoteEvent::VoiceTerminated { channel, .. }
+        | NoteEvent::PolyPressure { channel, .. }
+        | NoteEvent::PolyVolume { channel, .. }
+        | NoteEvent::PolyPan { channel, .. }
+        | NoteEvent::PolyTuning { channel, .. }
+        | NoteEvent::PolyVibrato { channel, .. }
+        | NoteEvent::PolyExpression { channel, .. }
+        | NoteEvent::PolyBrightness { channel, .. }
+        | NoteEvent::MidiChannelPressure { channel, .. }
+        | NoteEvent::MidiPitchBend { channel, .. }
+        | NoteEvent::MidiCC { channel, .. }
+        | NoteEvent::MidiProgramChange { channel, .. } => *channel = new_channel,
+        _ => (),
+    }
+    event
+}
+
+/// Whether an event starts, stops or changes a single note.
+fn is_note_event(event: &PluginNoteEvent<MidiInterpolator>) -> bool {
+    matches!(
+        event,
+        NoteEvent::NoteOn { .. }
+            | NoteEvent::NoteOff { .. }
+            | NoteEvent::Choke { .. }
+            | NoteEvent::VoiceTerminated { .. }
+            | NoteEvent::PolyModulation { .. }
+            | NoteEvent::PolyPressure { .. }
+            | NoteEvent::PolyVolume { .. }
+            | NoteEvent::PolyPan { .. }
+            | NoteEvent::PolyTuning { .. }
+            | NoteEvent::PolyVibrato { .. }
+            | NoteEvent::PolyExpression { .. }
+            | NoteEvent::PolyBrightness { .. }
+    )
+}
+
+/// An interpolated controller value, the scheduler sets its timing.
+fn morphed(channel: u8, controller: Controller, value: f32) -> PluginNoteEvent<MidiInterpolator> {
+    match controller {
+        Controller::Cc(cc) => NoteEvent::MidiCC {
+            timing: 0,
+            channel,
+            cc,
+            value,
+        },
+        Controller::PitchBend => NoteEvent::MidiPitchBend {
+            timing: 0,
+            channel,
+            value,
+        },
+        Controller::Pressure => NoteEvent::MidiChannelPressure {
+            timing: 0,
+            channel,
+            pressure: value,
+        },
+    }
+}
+
+/// Notes of every source that are interpolated together.
+#[derive(Default)]
+struct Group {
+    chords: [Chord; MAX_SOURCES],
+    /// The sources and their weights when the first note arrived.
+    sources: Sources,
+}
+
+impl Group {
+    /// The absolute sample of the first note.
+    fn start(&self) -> Option<u64> {
+        self.chords
+            .iter()
+            .filter(|chord| !chord.is_empty())
+            .map(|chord| chord.onset)
+            .min()
+    }
+
+    /// Whether a note of a source at `time` is interpolated together with this group. A source
+    /// that already played only takes more notes within the coincidence window of its chord, the
+    /// others can follow within the lookahead window.
+    fn accepts(&self, source: usize, time: u64, settings: &Settings) -> bool {
+        let chord = &self.chords[source];
+        match self.start() {
+            None => true,
+            Some(_) if !chord.is_empty() => time <= chord.onset + settings.coincidence,
+            Some(start) => time <= start + settings.lookahead,
         }
     }
+
+    /// Whether some chord of the group can still grow at `time`.
+    fn is_open(&self, time: u64, settings: &Settings) -> bool {
+        self.chords
+            .iter()
+            .any(|chord| !chord.is_empty() && time <= chord.onset + settings.coincidence)
+    }
+
+    fn add(&mut self, source: usize, note: u8, velocity: f32, time: u64, sources: &Sources) {
+        // The whole group is interpolated with the weights its first note arrived with
+        if self.start().is_none() {
+            self.sources = *sources;
+        }
+        self.chords[source].add(note, velocity, time);
+    }
 }
 
+/// The notes of every source that are waiting to be interpolated, kept across process calls.
 #[derive(Default)]
 struct NoteAverage {
-    cnt_a: u8,
-    cnt_b: u8,
-    note_a: u8,
-    note_b: u8,
-    velo_a: f32,
-    velo_b: f32,
+    /// The group that is interpolated next.
+    waiting: Group,
+    /// Notes that came too late for the waiting group while its chords could still grow. They
+    /// start a group of their own, which is interpolated after it.
+    next: Group,
+    /// The last chord of every source that was interpolated, with its NoteOffs so far.
+    recent: [Chord; MAX_SOURCES],
 }
 
 impl NoteAverage {
-    fn return_event(&mut self, interp: f32, timing: u32, channel: u8) -> Option<PluginNoteEvent<MidiInterpolator>> {
-        if self.cnt_a > 0 || self.cnt_b > 0 {
-            if self.cnt_a > 0 {
-                self.velo_a = self.velo_a / self.cnt_a as f32;
-                self.note_a = self.note_a / self.cnt_a;
-            } else {
-                self.note_a = self.note_b / self.cnt_b;
+    /// The absolute sample of the first waiting note.
+    fn start(&self) -> Option<u64> {
+        self.waiting.start()
+    }
+
+    /// Whether notes are waiting that nothing can join anymore at `time`.
+    fn is_due(&self, time: u64, window: u64) -> bool {
+        self.start().is_some_and(|start| time > start + window)
+    }
+
+    /// Interpolate the waiting group, the next one starts waiting. `send` gets every event with
+    /// the absolute sample it is due at. `roll` is a random number between 0 and 1 that picks the
+    /// source in the probabilistic mode.
+    fn return_events(
+        &mut self,
+        settings: &Settings,
+        roll: f32,
+        voices: &mut VoiceTable,
+        send: impl FnMut(u64, PluginNoteEvent<MidiInterpolator>),
+    ) {
+        let Some(start) = self.start() else {
+            return;
+        };
+
+        // Remember what every source played last, for the groups it misses later
+        for (recent, chord) in self.recent.iter_mut().zip(self.waiting.chords.iter()) {
+            if !chord.is_empty() {
+                *recent = *chord;
             }
-            if self.cnt_b > 0 {
-                self.velo_b = self.velo_b / self.cnt_b as f32;
-                self.note_b = self.note_b / self.cnt_b;
-            } else {
-                self.note_b = self.note_a;
+        }
+
+        self.interpolate_group(start, settings, roll, voices, send);
+
+        // reset tmps, in place because the chords are too big to be copied around
+        self.waiting.chords.iter_mut().for_each(Chord::clear);
+        std::mem::swap(&mut self.waiting, &mut self.next);
+    }
+
+    /// Interpolate the waiting notes of a group that started at the absolute sample `start`.
+    fn interpolate_group(
+        &mut self,
+        start: u64,
+        settings: &Settings,
+        roll: f32,
+        voices: &mut VoiceTable,
+        mut send: impl FnMut(u64, PluginNoteEvent<MidiInterpolator>),
+    ) {
+        let (group, recent) = (&mut self.waiting, &self.recent);
+        let count = group.sources.count;
+        let played: [u128; MAX_SOURCES] = std::array::from_fn(|index| group.chords[index].notes);
+
+        // Sources that didn't play are left out, unless something recent can stand in for them
+        let mut present = 0u16;
+        for (index, chord) in group.chords.iter_mut().enumerate().take(count) {
+            if !chord.is_empty() {
+                present |= 1 << index;
+                continue;
+            }
+            let context = &recent[index];
+            let fresh = !context.is_empty() && context.onset + settings.hold_timeout >= start;
+            *chord = match settings.missing_partner {
+                MissingPartner::Drop => return,
+                MissingPartner::LastHeld if fresh => context.stand_in(true, start),
+                MissingPartner::LastPlayed if fresh => context.stand_in(false, start),
+                _ => continue,
+            };
+            if !chord.is_empty() {
+                present |= 1 << index;
+            }
+        }
+        let chords = &group.chords;
+        let sources = group.sources.only(present);
+
+        // Picking a source is the same as interpolating all the way to it. Every source is picked
+        // with the probability of its weight, whether it played or not
+        let pick = group.sources.pick(roll);
+        let (weights, pitch_weights, velocity_weights) = match settings.mode {
+            InterpolationMode::Probabilistic => {
+                let weights = std::array::from_fn(|index| if index == pick { 1.0 } else { 0.0 });
+                (weights, weights, weights)
             }
+            _ => (sources.weights, sources.pitch_weights, sources.velocity_weights),
+        };
 
-            let new_note = self.note_a as f32 * (1.0 - interp) + self.note_b as f32 * interp;
-            let new_velo = self.velo_a * (1.0 - interp) + self.velo_b * interp;
+        // The onsets are interpolated too, so a rhythm on one source can move towards another
+        let offset: f64 = chords[..count]
+            .iter()
+            .zip(weights)
+            .map(|(chord, weight)| chord.onset.saturating_sub(start) as f64 * weight as f64)
+            .sum();
+        let onset = start + offset.round() as u64 + settings.latency;
+
+        // The picked source is played as it is
+        let space = match settings.mode {
+            InterpolationMode::Probabilistic => PitchSpace::Linear,
+            _ => settings.pitch_space,
+        };
+
+        let mut sent = 0u128;
+        // Gets the note and velocity of every source, and the input notes that make up the voice
+        let mut interpolate = |partners: &[(f32, f32); MAX_SOURCES], notes: &[u128; MAX_SOURCES]| {
+            let pitches = partners.map(|(note, _)| note);
+            let new_note = blend_pitch(space, &pitches, &pitch_weights, present, count);
+            let mut new_velo = 0.0;
+            for (index, (_, velocity)) in partners.iter().enumerate().take(count) {
+                new_velo += velocity * velocity_weights[index];
+            }
             //dbg!(new_velo);
             //dbg!(new_note);
+            // Without a scale the fraction is kept for microtonal output
+            let pitch = if settings.mode == InterpolationMode::Probabilistic {
+                new_note
+            } else if settings.output.is_microtonal() && !settings.quantizer.is_scale() {
+                new_note.clamp(0.0, 127.0)
+            } else {
+                settings.quantizer.quantize(new_note)
+            };
+            let channel = match settings.output {
+                OutputMode::PitchBend => {
+                    voices.allocate_channel(settings.bend.first_channel, settings.bend.pool_size())
+                }
+                _ => settings.output_channel,
+            };
+            // A key that is still sounding can't be retuned without moving that note too
+            let note = match settings.output {
+                OutputMode::Mts => voices.free_key(channel, pitch.round() as u8),
+                _ => pitch.round() as u8,
+            };
 
-            // reset tmps
-            self.cnt_a = 0;
-            self.note_a = 0;
-            self.velo_a = 0.0;
-            self.cnt_b = 0;
-            self.note_b = 0;
-            self.velo_b = 0.0;
-
-            // return average Note
-            Some(NoteEvent::NoteOn {
-                timing,
-                voice_id: None,
+            // remember where this note came from, so the NoteOffs of the sources can stop it
+            let with_id = settings.output == OutputMode::NoteTuning;
+            let Some(voice) = voices.start_voice(channel, note, with_id, onset) else {
+                return;
+            };
+            let voice_id = voices.id(voice);
+            for index in 0..count {
+                let (chan, onset) = (sources.channels[index], chords[index].onset);
+                voices.add_sources(voice, chan, notes[index], onset, weights[index]);
+            }
+
+            match settings.output {
+                OutputMode::Semitones => {
+                    // Two pairs can land on the same note, that one only needs to be started once
+                    if sent & (1u128 << note) != 0 {
+                        return;
+                    }
+                    sent |= 1u128 << note;
+                }
+                OutputMode::PitchBend => {
+                    send(onset, NoteEvent::MidiPitchBend {
+                        timing: 0,
+                        channel,
+                        value: settings.bend.value(pitch - note as f32),
+                    });
+                }
+                OutputMode::NoteTuning => {}
+                OutputMode::Mts => {
+                    send(onset, NoteEvent::MidiSysEx {
+                        timing: 0,
+                        message: SysEx::NoteTuning { key: note, pitch },
+                    });
+                }
+            }
+            send(onset, NoteEvent::NoteOn {
+                timing: 0,
+                voice_id,
                 channel,
-                note: new_note.round() as u8,
+                note,
                 velocity: new_velo,
-            })
-        } else { None }
+            });
+            if settings.output == OutputMode::NoteTuning {
+                send(onset, NoteEvent::PolyTuning {
+                    timing: 0,
+                    voice_id,
+                    channel,
+                    note,
+                    tuning: pitch - note as f32,
+                });
+            }
+        };
+
+        match settings.mode {
+            InterpolationMode::Average => {
+                let partners = std::array::from_fn(|index| chords[index].average());
+                interpolate(&partners, &played);
+            }
+            InterpolationMode::Polyphonic | InterpolationMode::VoiceLeading => {
+                // Every source is paired with the biggest chord, so each of its notes becomes a
+                // voice and finds one partner in every other chord
+                let reference = (0..count)
+                    .rev()
+                    .max_by_key(|&index| chords[index].len())
+                    .unwrap_or(0);
+                let mut partners = [[(0u8, 0.0f32); MAX_SOURCES]; 128];
+                let below = |note: u8| chords[reference].notes & ((1u128 << note) - 1);
+                for index in 0..count {
+                    let pair = |(note_r, _): (u8, f32), partner: (u8, f32)| {
+                        partners[below(note_r).count_ones() as usize][index] = partner;
+                    };
+                    if settings.mode == InterpolationMode::Polyphonic {
+                        pair_by_rank(&chords[reference], &chords[index], pair);
+                    } else {
+                        pair_by_voice_leading(&chords[reference], &chords[index], pair);
+                    }
+                }
+
+                for voice in partners.iter().take(chords[reference].len()) {
+                    let notes = std::array::from_fn(|index| {
+                        played[index] & (1u128 << voice[index].0)
+                    });
+                    interpolate(&voice.map(|(note, velocity)| (note as f32, velocity)), &notes);
+                }
+            }
+            InterpolationMode::Probabilistic => {
+                // A source that didn't play is picked as silence. Stand-ins aren't played either,
+                // they have no NoteOffs that could end their notes
+                for note in Notes(played[pick]) {
+                    let partners = [(note as f32, chords[pick].velocity(note)); MAX_SOURCES];
+                    let notes = std::array::from_fn(|index| {
+                        if index == pick { 1u128 << note } else { 0 }
+                    });
+                    interpolate(&partners, &notes);
+                }
+            }
+        }
+
+        // Notes that already ended while waiting
+        for (chord, &chan) in chords.iter().zip(sources.channels.iter()).take(count) {
+            for (note, end) in chord.iter_released() {
+                voices.release_source(chan, note, end, |channel, note, voice_id, time| {
+                    send(time, note_off(channel, note, voice_id, 0.0));
+                });
+            }
+        }
+    }
+
+    /// Add a note of a source at `time` to the group it is interpolated with. A note that the
+    /// waiting group can't take starts the next group, as long as the waiting one is still open.
+    /// Returns false if no group can take the note, the waiting group has to be interpolated
+    /// first then.
+    fn advance(
+        &mut self,
+        source: usize,
+        note: u8,
+        velocity: f32,
+        time: u64,
+        settings: &Settings,
+    ) -> bool {
+        let (waiting, next) = (&mut self.waiting, &mut self.next);
+        let group = if waiting.accepts(source, time, settings) {
+            waiting
+        } else if waiting.is_open(time, settings) && next.accepts(source, time, settings) {
+            next
+        } else {
+            return false;
+        };
+        group.add(source, note, velocity, time, &settings.sources);
+        true
+    }
+
+    /// Forget the waiting notes and the recent chords.
+    fn clear(&mut self) {
+        let groups = self.waiting.chords.iter_mut().chain(self.next.chords.iter_mut());
+        groups.chain(self.recent.iter_mut()).for_each(Chord::clear);
+    }
+
+    /// Remember the NoteOff of a note that was already interpolated, so it no longer counts as
+    /// held.
+    fn release_recent(&mut self, source: usize, note: u8, time: u64) {
+        if self.recent[source].contains(note) {
+            self.recent[source].release(note, time);
+        }
     }
 
-    fn advance_a(&mut self, note: u8, velocity: f32) -> () {
-        self.cnt_a += 1;
-        self.note_a += note;
-        self.velo_a += velocity;
+    /// Remember the NoteOff of a note that is still waiting. If both groups have the note, the one
+    /// in the waiting group has already ended.
+    fn release(&mut self, source: usize, note: u8, time: u64) {
+        let group = if self.next.chords[source].contains(note) {
+            &mut self.next
+        } else {
+            &mut self.waiting
+        };
+        group.chords[source].release(note, time);
     }
 
-    fn advance_b(&mut self, note: u8, velocity: f32) -> () {
-        self.cnt_b += 1;
-        self.note_b += note;
-        self.velo_b += velocity;
+    /// Whether a note is still waiting to be interpolated.
+    fn is_pending(&self, source: usize, note: u8) -> bool {
+        self.waiting.chords[source].contains(note) || self.next.chords[source].contains(note)
+    }
+}
+
+impl MidiInterpolator {
+    /// A length in milliseconds in samples.
+    fn samples(&self, ms: f32) -> u32 {
+        (ms * self.sample_rate / 1000.0).round() as u32
+    }
+
+    /// The latency that leaves room for every note of a group to arrive: the last source can join
+    /// at the end of the lookahead window, and its chord can still grow for the coincidence window.
+    fn latency_samples(&self) -> u32 {
+        self.samples(self.params.lookahead.value()) + self.samples(self.params.coincidence.value())
+    }
+
+    /// Interpolate the waiting notes and schedule the results.
+    fn return_notes(&mut self, settings: &Settings) {
+        let roll = match settings.mode {
+            InterpolationMode::Probabilistic => self.rng.next_f32(),
+            _ => 0.0,
+        };
+        let scheduler = &mut self.scheduler;
+        let voices = &mut self.voices;
+        self.note_average.return_events(settings, roll, voices, |time, event| {
+            scheduler.push(time, event)
+        });
+    }
+
+    /// The sources with their smoothed weights at `timing`, moving the smoothers along from the
+    /// sample they were last moved to.
+    fn sources_at(&self, smoothed_to: &mut u32, timing: u32) -> Sources {
+        let steps = timing.saturating_sub(*smoothed_to);
+        *smoothed_to = (*smoothed_to).max(timing);
+        let smoothed = |param: &FloatParam| {
+            if steps > 0 {
+                param.smoothed.next_step(steps)
+            } else {
+                param.smoothed.previous_value()
+            }
+        };
+
+        let params = &self.params;
+        let interp = smoothed(&params.interpolate_a_b);
+        let interp = match (self.controlled, steps) {
+            (false, _) => interp,
+            (true, 0) => self.control.previous_value(),
+            (true, _) => self.control.next_step(steps),
+        };
+        let weights: [f32; MAX_SOURCES] =
+            std::array::from_fn(|index| smoothed(&params.sources[index].weight));
+        let (x, y) = (smoothed(&params.pad_x), smoothed(&params.pad_y));
+        let (pitch, velocity) = (smoothed(&params.pitch_morph), smoothed(&params.velocity_morph));
+
+        let (weights, used) = match params.layout.value() {
+            SourceLayout::AB => {
+                let sources = Sources::a_b(
+                    params.channel_a.value() as u8 - 1,
+                    params.channel_b.value() as u8 - 1,
+                    interp,
+                );
+                // The first two sources stand for A and B
+                let passthrough = params.sources.iter().map(|source| source.passthrough.value());
+                let (pitch, velocity) = if params.link_morph.value() {
+                    (interp, interp)
+                } else {
+                    (pitch, velocity)
+                };
+                return sources
+                    .with_amounts(
+                        params.pitch_curve.curve().apply(pitch),
+                        params.velocity_curve.curve().apply(velocity),
+                    )
+                    .with_passthrough(passthrough);
+            }
+            SourceLayout::Vector => (weights, MAX_SOURCES),
+            SourceLayout::XyPad => {
+                // The first four sources are the corners, bottom left, bottom right, top left and
+                // top right
+                let mut corners = [0.0; MAX_SOURCES];
+                corners[..4].copy_from_slice(&[
+                    (1.0 - x) * (1.0 - y),
+                    x * (1.0 - y),
+                    (1.0 - x) * y,
+                    x * y,
+                ]);
+                (corners, 4)
+            }
+        };
+        // Sources that are switched off are left out, the others share their weight
+        let switched_on = params
+            .sources
+            .iter()
+            .zip(weights)
+            .take(used)
+            .filter(|(source, _)| source.channel.value() > 0);
+        Sources::weighted(
+            switched_on.clone().map(|(source, weight)| (source.channel.value() as u8 - 1, weight)),
+        )
+            .with_passthrough(switched_on.map(|(source, _)| source.passthrough.value()))
+    }
+
+    /// Start the random choices over from the seed parameter.
+    fn reseed(&mut self) {
+        self.seed = self.params.seed.value();
+        self.rng = Rng::new(self.seed as u64);
+    }
+
+    /// Stop every note that was sent at the absolute sample `time`, and forget the notes that are
+    /// still waiting, so no note is left hanging. Queued controllers and other events are still
+    /// sent.
+    fn flush(&mut self, time: u64) {
+        self.scheduler.remove(is_note_event);
+        self.note_average.clear();
+        self.voices.clear();
+        for (channel, notes) in self.sent_notes.iter().enumerate() {
+            for (note, _) in notes.iter().enumerate().filter(|(_, &count)| count > 0) {
+                self.scheduler.push(time, note_off(channel as u8, note as u8, None, 0.0));
+            }
+        }
+    }
+
+    /// Send the events that are due in this block, keeping track of the notes that are sounding.
+    fn send_due(
+        &mut self,
+        block_start: u64,
+        block_end: u64,
+        context: &mut impl ProcessContext<Self>,
+    ) {
+        let sent_notes = &mut self.sent_notes;
+        self.scheduler.send_due(block_start, block_end, |event| {
+            match event {
+                NoteEvent::NoteOn { channel, note, .. } => {
+                    let count = &mut sent_notes[channel as usize][note as usize];
+                    *count = count.saturating_add(1);
+                }
+                // Without a voice ID, a NoteOff stops every voice on that note
+                NoteEvent::NoteOff { channel, note, voice_id, .. } => {
+                    let count = &mut sent_notes[channel as usize][note as usize];
+                    *count = if voice_id.is_some() { count.saturating_sub(1) } else { 0 };
+                }
+                _ => (),
+            }
+            context.send_event(event);
+        });
+        self.position = block_end;
+    }
+
+    /// Read the Scala files from the parameters again. Files that can't be read leave the notes
+    /// in 12-TET.
+    fn load_tuning(&mut self) {
+        let (Ok(scale), Ok(mapping)) =
+            (self.params.scala_scale.try_read(), self.params.scala_mapping.try_read())
+        else {
+            // The editor is writing them right now, try again in the next block
+            self.params.tuning_changed.store(true, SeqCst);
+            return;
+        };
+        self.tuning = if scale.trim().is_empty() {
+            None
+        } else {
+            Tuning::parse(&scale, &mapping).ok()
+        };
+    }
+
+    /// The scale the interpolated notes are snapped to, from the current parameter values.
+    fn quantizer(&self) -> Quantizer {
+        let params = &self.params;
+        let mask = params.scale.value().mask().unwrap_or_else(|| {
+            params
+                .custom_scale
+                .iter()
+                .enumerate()
+                .filter(|(_, degree)| degree.enabled.value())
+                .fold(0, |mask, (step, _)| mask | 1 << step)
+        });
+
+        Quantizer {
+            root: params.root.value() as u8,
+            mask,
+            rounding: params.rounding.value(),
+            tuning: self.tuning.map(|tuning| tuning.with_reference(params.reference_pitch.value())),
+        }
+    }
+
+    /// Take a controller value from a source and queue the interpolated one, if it changed.
+    fn morph_controller(
+        &mut self,
+        settings: &Settings,
+        controller: Controller,
+        source: usize,
+        value: f32,
+        time: u64,
+    ) {
+        if let Some(value) = self.controllers.set(controller, source, value, &settings.sources) {
+            let event = morphed(settings.control_channel(), controller, value);
+            self.scheduler.push(time + settings.latency, event);
+        }
+    }
+
+    /// Send the interpolated poly pressure to every voice the input note is part of.
+    fn morph_poly_pressure(&mut self, settings: &Settings, channel: u8, note: u8, time: u64) {
+        let (controllers, scheduler) = (&self.controllers, &mut self.scheduler);
+        let sources = &settings.sources;
+        self.voices.voices_of(channel, note, |voice| {
+            let mut values = [None; MAX_SOURCES];
+            for &(channel, notes) in voice.inputs.iter().filter(|(_, notes)| *notes != 0) {
+                if let Some(source) = sources.index_of(channel) {
+                    values[source] = controllers.poly_pressure(source, notes);
+                }
+            }
+            let values = values.into_iter().zip(sources.weights).take(sources.count);
+            if let Some(pressure) = blend(values) {
+                let event = NoteEvent::PolyPressure {
+                    timing: 0,
+                    voice_id: voice.id,
+                    channel: voice.channel,
+                    note: voice.note,
+                    pressure,
+                };
+                scheduler.push(time + settings.latency, event);
+            }
+        });
     }
 }
 
@@ -138,7 +1113,7 @@ impl Plugin for MidiInterpolator {
     const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
+    type SysExMessage = SysEx;
     type BackgroundTask = ();
 
     fn params(&self) -> Arc<dyn Params> {
@@ -155,62 +1130,228 @@ impl Plugin for MidiInterpolator {
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
-        _buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        buffer_config: &BufferConfig,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+        self.latency = self.latency_samples();
+        context.set_latency_samples(self.latency);
+        // The state may have been restored with other Scala files
+        self.load_tuning();
+
         true
     }
 
+    fn reset(&mut self) {
+        // Also called when the transport restarts or a state is loaded. The NoteOffs go out at the
+        // start of the next block
+        self.flush(self.position);
+        // Start the random choices over, so every render makes the same ones
+        self.reseed();
+    }
+
     fn process(
         &mut self,
-        _buffer: &mut Buffer,
+        buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        // TODO this doesn't handle durations (noteoffs), NoteOffs are sent even if the corresponding On wasn't
-        // TODO change channel of output to note that has a Noteoff! Else notes sustend
+        let latency = self.latency_samples();
+        if latency != self.latency {
+            context.set_latency_samples(latency);
+            self.latency = latency;
+        }
+
+        if self.params.seed.value() != self.seed {
+            self.reseed();
+        }
+        if self.params.tuning_changed.swap(false, SeqCst) {
+            self.load_tuning();
+        }
+
+        // The smoothers are moved along to every event, so fast automation reaches every note
+        let mut smoothed_to = 0;
+        let mut settings = Settings {
+            mode: self.params.mode.value(),
+            sources: self.sources_at(&mut smoothed_to, 0),
+            quantizer: self.quantizer(),
+            pitch_space: self.params.pitch_space.value(),
+            output: self.params.output_mode.value(),
+            output_channel: self.params.output_channel.value() as u8 - 1,
+            other_channels: self.params.other_channels.value(),
+            bend: BendSettings {
+                first_channel: self.params.mpe_first_channel.value() as u8 - 1,
+                channels: self.params.mpe_channels.value() as u8,
+                range: self.params.bend_range.value() as u8,
+            },
+            lookahead: self.samples(self.params.lookahead.value()) as u64,
+            coincidence: self.samples(self.params.coincidence.value()) as u64,
+            latency: latency as u64,
+            missing_partner: self.params.missing_partner.value(),
+            hold_timeout: self.samples(self.params.hold_timeout.value()) as u64,
+            morph_ccs: self.params.morph_ccs.iter().fold(0, |ccs, slot| {
+                let cc = slot.cc.value();
+                if cc < 0 { ccs } else { ccs | 1u128 << cc }
+            }),
+        };
+
+        // Synths need to know the pitch bend range before the first bent note
+        if settings.output == OutputMode::PitchBend && self.announced_bend != Some(settings.bend) {
+            settings.bend.announce(0, |event| context.send_event(event));
+            self.announced_bend = Some(settings.bend);
+        }
+
+        let block_start = self.position;
+        let block_end = block_start + buffer.samples() as u64;
 
-        let interp = self.params.interpolate_a_b.value(); // TODO get this here?
-        let chan_a = self.params.channel_a.load(SeqCst) - 1;
-        let chan_b = self.params.channel_b.load(SeqCst) - 1;
-        let mut note_average = NoteAverage::default();
-        let mut last_timing = 0;
+        // Notes would hang if the transport stops, bypass is switched or the channels change.
+        // Notes that were passed on while bypassed don't reach the synth as sources
+        let playing = context.transport().playing;
+        let bypassed = self.params.bypass.value();
+        let routing = (settings.sources.channels, settings.sources.count, settings.output_channel);
+        if (self.playing && !playing) || bypassed != self.bypassed || routing != self.routing {
+            self.flush(block_start);
+        }
+        (self.playing, self.bypassed, self.routing) = (playing, bypassed, routing);
+
+        if bypassed {
+            // Still delayed by the latency the host compensates for
+            while let Some(event) = context.next_event() {
+                let time = block_start + event.timing() as u64;
+                self.scheduler.push(time + settings.latency, event);
+            }
+            self.send_due(block_start, block_end, context);
+            return ProcessStatus::Normal;
+        }
+
+        let control = self.params.control_source.value();
+        let control_channel = self.params.control_channel.value() as u8 - 1;
+        let control_cc = self.params.control_cc.value() as u8;
+        if control == ControlSource::None {
+            self.controlled = false;
+        }
+
+        // Morphed controllers follow the interpolation even when the sources don't move
+        let scheduler = &mut self.scheduler;
+        self.controllers.update(&settings.sources, settings.morph_ccs, |controller, value| {
+            let event = morphed(settings.control_channel(), controller, value);
+            scheduler.push(block_start + settings.latency, event);
+        });
 
         while let Some(event) = context.next_event() {
-            match event {
-                NoteEvent::NoteOn {
-                    timing,
+            let time = block_start + event.timing() as u64;
+            settings.sources = self.sources_at(&mut smoothed_to, event.timing());
+
+            // If nothing can join the waiting notes anymore, return their average
+            while self.note_average.is_due(time, settings.latency) {
+                self.return_notes(&settings);
+            }
+
+            // The morph control is used up, it isn't a source or passed on
+            if let Some(amount) = control.amount(&event, control_channel, control_cc) {
+                if let Some(amount) = amount {
+                    if self.controlled {
+                        self.control.set_target(self.sample_rate, amount);
+                    } else {
+                        self.control.reset(amount);
+                        self.controlled = true;
+                    }
+                }
+                continue;
+            }
+
+            let source = event.channel().and_then(|channel| settings.sources.index_of(channel));
+            match (source, event) {
+                (_, event @ NoteEvent::MidiCC { cc: 120 | 123, .. }) => {
+                    // All Sound Off and All Notes Off stop the interpolated notes too
+                    self.flush(time);
+                    self.scheduler.push(time + settings.latency, event);
+                }
+                (Some(source), event @ NoteEvent::NoteOn { note, velocity, .. }) => {
+                    if settings.sources.passthrough & (1 << source) != 0 {
+                        self.scheduler.push(time + settings.latency, event);
+                    }
+
+                    // Another chord on the same source starts over, once the waiting ones are done
+                    while !self.note_average.advance(source, note, velocity, time, &settings) {
+                        self.return_notes(&settings);
+                    }
+                },
+                (Some(source), event @ NoteEvent::NoteOff {
                     channel,
                     note,
                     velocity,
                     ..
-                } => {
-                    // If this note is not at the same time as the last, return last notes average
-                    if timing > last_timing {
-                       if let Some(event) = note_average.return_event(interp, timing, channel) {
-                           context.send_event(event);
-                       }
+                }) => {
+                    if settings.sources.passthrough & (1 << source) != 0 {
+                        self.scheduler.push(time + settings.latency, event);
                     }
-                    last_timing = timing;
 
-                    // Increase Average by this note
-                    if channel as usize == chan_a {
-                        note_average.advance_a(note, velocity);
-                    } else if channel as usize == chan_b {
-                        note_average.advance_b(note, velocity);
+                    if self.note_average.is_pending(source, note) {
+                        // A note that ends before it was interpolated is taken care of after
+                        self.note_average.release(source, note, time);
                     } else {
-                        context.send_event(event);
+                        self.note_average.release_recent(source, note, time);
+
+                        // The interpolated notes this one was part of may be able to stop now
+                        let scheduler = &mut self.scheduler;
+                        self.voices.release_source(
+                            channel,
+                            note,
+                            time,
+                            |channel, note, voice_id, time| {
+                                scheduler.push(time, note_off(channel, note, voice_id, velocity));
+                            },
+                        );
                     }
                 },
-                _ => context.send_event(event),
+                (Some(source), NoteEvent::MidiCC { cc, value, .. })
+                    if settings.morph_ccs & (1u128 << cc) != 0 =>
+                {
+                    self.morph_controller(&settings, Controller::Cc(cc), source, value, time);
+                },
+                (Some(source), NoteEvent::MidiPitchBend { value, .. }) => {
+                    self.morph_controller(&settings, Controller::PitchBend, source, value, time);
+                },
+                (Some(source), NoteEvent::MidiChannelPressure { pressure, .. }) => {
+                    self.morph_controller(&settings, Controller::Pressure, source, pressure, time);
+                },
+                (Some(source), NoteEvent::PolyPressure {
+                    channel,
+                    note,
+                    pressure,
+                    ..
+                }) => {
+                    self.controllers.set_poly_pressure(source, note, pressure);
+                    self.morph_poly_pressure(&settings, channel, note, time);
+                },
+                (Some(_), event) => self.scheduler.push(time + settings.latency, event),
+                (None, event) => match settings.other_channels {
+                    OtherChannels::Block if event.channel().is_some() => (),
+                    OtherChannels::Remap => {
+                        let event = set_channel(event, settings.output_channel);
+                        self.scheduler.push(time + settings.latency, event);
+                    }
+                    _ => self.scheduler.push(time + settings.latency, event),
+                },
             }
         }
 
-        // get the last event out if necessary
-        if let Some(event) = note_average.return_event(interp, last_timing, chan_a as u8) {
-            context.send_event(event);
+        self.sources_at(&mut smoothed_to, buffer.samples() as u32);
+
+        // get the last notes out if nothing can join them anymore
+        while self.note_average.is_due(block_end, settings.latency) {
+            self.return_notes(&settings);
         }
 
+        // Notes whose length has run out can't wait for their inputs anymore
+        let scheduler = &mut self.scheduler;
+        self.voices.release_overdue(block_end, |channel, note, voice_id, time| {
+            scheduler.push(time, note_off(channel, note, voice_id, 0.0));
+        });
+
+        self.send_due(block_start, block_end, context);
+
         ProcessStatus::Normal
     }
 }
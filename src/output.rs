@@ -0,0 +1,78 @@
+// How interpolated pitches are sent to the synth.
+use nih_plug::prelude::*;
+use crate::MidiInterpolator;
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum OutputMode {
+    /// Round to the closest MIDI note.
+    #[name = "Semitones"]
+    Semitones,
+    /// The closest MIDI note plus a pitch bend for the rest, every note on its own channel.
+    #[name = "Pitch Bend (MPE)"]
+    PitchBend,
+    /// The closest MIDI note with its own voice ID, plus a CLAP note expression for the rest.
+    #[name = "CLAP Note Tuning"]
+    NoteTuning,
+    /// A free key close to the pitch, retuned with a MIDI Tuning Standard SysEx message first.
+    /// The key stays retuned for everything else that plays it.
+    #[name = "MIDI Tuning Standard"]
+    Mts,
+}
+
+impl OutputMode {
+    /// Whether pitches between the semitones can be played.
+    pub fn is_microtonal(self) -> bool {
+        self != OutputMode::Semitones
+    }
+}
+
+/// What happens to the events on channels that aren't morphed.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum OtherChannels {
+    /// Send them on as they are.
+    #[name = "Pass"]
+    Pass,
+    /// Drop them.
+    #[name = "Block"]
+    Block,
+    /// Move them to the output channel.
+    #[name = "Remap to Output"]
+    Remap,
+}
+
+/// The channels that pitch bent notes are spread over, and their pitch bend range.
+#[derive(Clone, Copy, PartialEq)]
+pub struct BendSettings {
+    pub first_channel: u8,
+    pub channels: u8,
+    /// In semitones, up and down.
+    pub range: u8,
+}
+
+impl BendSettings {
+    /// The number of channels in the pool, without going past channel 16.
+    pub fn pool_size(&self) -> u8 {
+        self.channels.clamp(1, 16 - self.first_channel.min(15))
+    }
+
+    /// The normalized pitch bend that moves a note by `semitones`.
+    pub fn value(&self, semitones: f32) -> f32 {
+        (0.5 + semitones / (2.0 * self.range as f32)).clamp(0.0, 1.0)
+    }
+
+    /// Tell the synth about the pitch bend range of every channel in the pool, with RPN 0.
+    pub fn announce(&self, timing: u32, mut send: impl FnMut(PluginNoteEvent<MidiInterpolator>)) {
+        for channel in self.first_channel..self.first_channel + self.pool_size() {
+            // select RPN 0, set semitones and cents, then deselect it again
+            let messages = [(101, 0), (100, 0), (6, self.range), (38, 0), (101, 127), (100, 127)];
+            for (cc, value) in messages {
+                send(NoteEvent::MidiCC {
+                    timing,
+                    channel,
+                    cc,
+                    value: value as f32 / 127.0,
+                });
+            }
+        }
+    }
+}
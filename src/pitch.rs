@@ -0,0 +1,88 @@
+// The ways pitches can be blended, besides a straight line between the MIDI note numbers.
+use nih_plug::prelude::*;
+use crate::sources::MAX_SOURCES;
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum PitchSpace {
+    /// Straight between the MIDI note numbers.
+    #[name = "Linear"]
+    Linear,
+    /// The shortest way around the circle of pitch classes, in the octave closest to A.
+    #[name = "Pitch Class (Octave of A)"]
+    CircleA,
+    /// The shortest way around the circle of pitch classes, in the octave closest to B.
+    #[name = "Pitch Class (Octave of B)"]
+    CircleB,
+    /// The shortest way around the circle of pitch classes, in the octave closest to the linear
+    /// blend.
+    #[name = "Pitch Class (Blended Octave)"]
+    CircleBlended,
+    /// Between the frequencies, which leans towards the higher notes.
+    #[name = "Frequency"]
+    Frequency,
+}
+
+/// Blend the pitches (fractional MIDI notes) of the sources. Only the first `count` sources whose
+/// bit is set in `present` take part, their weights have to add up to 1.
+pub fn blend_pitch(
+    space: PitchSpace,
+    pitches: &[f32; MAX_SOURCES],
+    weights: &[f32; MAX_SOURCES],
+    present: u16,
+    count: usize,
+) -> f32 {
+    let sources = || {
+        pitches
+            .iter()
+            .zip(weights.iter())
+            .enumerate()
+            .take(count)
+            .filter(move |(index, _)| present & (1 << index) != 0)
+            .map(|(_, (&pitch, &weight))| (pitch, weight))
+    };
+    let linear: f32 = sources().map(|(pitch, weight)| pitch * weight).sum();
+
+    match space {
+        PitchSpace::Linear => linear,
+        PitchSpace::Frequency => {
+            let frequency: f32 =
+                sources().map(|(pitch, weight)| to_frequency(pitch) * weight).sum();
+            if frequency > 0.0 { to_pitch(frequency) } else { linear }
+        }
+        PitchSpace::CircleA | PitchSpace::CircleB | PitchSpace::CircleBlended => {
+            // Walk from the first source towards every other one, the short way round
+            let Some((anchor, _)) = sources().next() else {
+                return linear;
+            };
+            let pitch_class: f32 = anchor
+                + sources()
+                    .map(|(pitch, weight)| shortest(pitch - anchor) * weight)
+                    .sum::<f32>();
+
+            let octave_of = |index: usize| {
+                if index < count && present & (1 << index) != 0 { pitches[index] } else { linear }
+            };
+            let near = match space {
+                PitchSpace::CircleA => octave_of(0),
+                PitchSpace::CircleB => octave_of(1),
+                _ => linear,
+            };
+            near + shortest(pitch_class - near)
+        }
+    }
+}
+
+/// A distance in semitones, turned into the shortest one that reaches the same pitch class.
+fn shortest(distance: f32) -> f32 {
+    let distance = distance.rem_euclid(12.0);
+    if distance > 6.0 { distance - 12.0 } else { distance }
+}
+
+/// In Hz, with A4 at 440 Hz. The blend doesn't depend on the tuning of A4.
+fn to_frequency(pitch: f32) -> f32 {
+    440.0 * 2f32.powf((pitch - 69.0) / 12.0)
+}
+
+fn to_pitch(frequency: f32) -> f32 {
+    69.0 + 12.0 * (frequency / 440.0).log2()
+}
@@ -0,0 +1,176 @@
+// Snapping interpolated pitches to the notes of a scale.
+use nih_plug::prelude::*;
+use crate::tuning::Tuning;
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Root {
+    #[name = "C"]
+    C,
+    #[name = "C#"]
+    CSharp,
+    #[name = "D"]
+    D,
+    #[name = "D#"]
+    DSharp,
+    #[name = "E"]
+    E,
+    #[name = "F"]
+    F,
+    #[name = "F#"]
+    FSharp,
+    #[name = "G"]
+    G,
+    #[name = "G#"]
+    GSharp,
+    #[name = "A"]
+    A,
+    #[name = "A#"]
+    ASharp,
+    #[name = "B"]
+    B,
+}
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Scale {
+    /// Every semitone, so pitches are only rounded.
+    #[name = "Chromatic"]
+    Chromatic,
+    #[name = "Major"]
+    Major,
+    #[name = "Minor"]
+    Minor,
+    #[name = "Harmonic Minor"]
+    HarmonicMinor,
+    #[name = "Melodic Minor"]
+    MelodicMinor,
+    #[name = "Dorian"]
+    Dorian,
+    #[name = "Phrygian"]
+    Phrygian,
+    #[name = "Lydian"]
+    Lydian,
+    #[name = "Mixolydian"]
+    Mixolydian,
+    #[name = "Locrian"]
+    Locrian,
+    #[name = "Major Pentatonic"]
+    MajorPentatonic,
+    #[name = "Minor Pentatonic"]
+    MinorPentatonic,
+    /// The degrees chosen in the custom scale parameters.
+    #[name = "Custom"]
+    Custom,
+}
+
+impl Scale {
+    /// The pitch classes of the scale, bit `i` standing for `i` semitones above the root. `None`
+    /// for the custom scale.
+    pub fn mask(self) -> Option<u16> {
+        let steps: &[u16] = match self {
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Custom => return None,
+        };
+
+        Some(steps.iter().fold(0, |mask, step| mask | 1 << step))
+    }
+}
+
+/// Which way a pitch between two scale notes is moved.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Rounding {
+    #[name = "Nearest"]
+    Nearest,
+    #[name = "Up"]
+    Up,
+    #[name = "Down"]
+    Down,
+}
+
+/// The names of the custom scale degrees, counted from the root.
+const DEGREE_NAMES: [&str; 12] = ["1", "b2", "2", "b3", "3", "4", "b5", "5", "b6", "6", "b7", "7"];
+
+/// One degree of the custom scale.
+#[derive(Params)]
+pub struct ScaleDegreeParams {
+    #[id = "degree"]
+    pub enabled: BoolParam,
+}
+
+impl ScaleDegreeParams {
+    /// The parameter for the degree `step` semitones above the root. Defaults to a major scale.
+    pub fn new(step: usize) -> Self {
+        let in_major = Scale::Major.mask().unwrap_or(0) & (1 << step) != 0;
+        Self {
+            enabled: BoolParam::new(format!("Degree {}", DEGREE_NAMES[step]), in_major),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Quantizer {
+    pub root: u8,
+    /// Bit `i` is set if the note `i` semitones above the root belongs to the scale.
+    pub mask: u16,
+    pub rounding: Rounding,
+    /// A Scala tuning, which replaces the root and the scale.
+    pub tuning: Option<Tuning>,
+}
+
+impl Quantizer {
+    /// Whether the scale leaves out any semitones or is tuned differently, so there is something
+    /// to snap to besides the closest note.
+    pub fn is_scale(&self) -> bool {
+        if self.tuning.is_some() {
+            return true;
+        }
+        let mask = self.mask & 0xfff;
+        mask != 0 && mask != 0xfff
+    }
+
+    /// Move a fractional MIDI pitch onto a note of the scale.
+    pub fn quantize(&self, pitch: f32) -> f32 {
+        if let Some(tuning) = &self.tuning {
+            return tuning.quantize(pitch, self.rounding);
+        }
+        if self.mask & 0xfff == 0 {
+            // nothing to snap to
+            return pitch.round();
+        }
+
+        let down = self.find(pitch.floor() as i32, -1);
+        let up = self.find(pitch.ceil() as i32, 1);
+        let note = match self.rounding {
+            Rounding::Down => down,
+            Rounding::Up => up,
+            Rounding::Nearest => {
+                if pitch - (down as f32) < (up as f32) - pitch { down } else { up }
+            }
+        };
+        // Rather go the other way than leave the MIDI range
+        let note = if note > 127 { down } else if note < 0 { up } else { note };
+
+        note.clamp(0, 127) as f32
+    }
+
+    /// The closest note of the scale, starting at `note` and walking `step` semitones at a time.
+    fn find(&self, mut note: i32, step: i32) -> i32 {
+        for _ in 0..12 {
+            if self.mask & (1 << (note - self.root as i32).rem_euclid(12)) != 0 {
+                break;
+            }
+            note += step;
+        }
+        note
+    }
+}
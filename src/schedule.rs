@@ -0,0 +1,77 @@
+// Events that are sent at a later time, possibly several buffers after they were created.
+use nih_plug::prelude::*;
+use crate::MidiInterpolator;
+
+/// How many events can wait at the same time. The queue is allocated once, so nothing needs to be
+/// allocated on the audio thread.
+const CAPACITY: usize = 4096;
+
+pub struct Scheduler {
+    /// Events and the absolute sample they are due at, sorted by time. Events with the same time
+    /// keep the order they were added in.
+    events: Vec<(u64, PluginNoteEvent<MidiInterpolator>)>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            events: Vec::with_capacity(CAPACITY),
+        }
+    }
+}
+
+impl Scheduler {
+    /// Queue an event to be sent at the absolute sample `time`. The event's own timing is ignored.
+    pub fn push(&mut self, time: u64, event: PluginNoteEvent<MidiInterpolator>) {
+        if self.events.len() == CAPACITY {
+            nih_debug_assert_failure!("Too many scheduled events, dropping one");
+            return;
+        }
+
+        let index = self.events.partition_point(|(other, _)| *other <= time);
+        self.events.insert(index, (time, event));
+    }
+
+    /// Drop the waiting events that `drop` picks, the others keep their order.
+    pub fn remove(&mut self, mut drop: impl FnMut(&PluginNoteEvent<MidiInterpolator>) -> bool) {
+        self.events.retain(|(_, event)| !drop(event));
+    }
+
+    /// Send every event that is due before `block_end`, with its timing relative to `block_start`.
+    pub fn send_due(
+        &mut self,
+        block_start: u64,
+        block_end: u64,
+        mut send: impl FnMut(PluginNoteEvent<MidiInterpolator>),
+    ) {
+        let due = self.events.partition_point(|(time, _)| *time < block_end);
+        for (time, mut event) in self.events.drain(..due) {
+            set_timing(&mut event, time.saturating_sub(block_start) as u32);
+            send(event);
+        }
+    }
+}
+
+fn set_timing(event: &mut PluginNoteEvent<MidiInterpolator>, new_timing: u32) {
+    match event {
+        NoteEvent::NoteOn { timing, .. }
+        | NoteEvent::NoteOff { timing, .. }
+        | NoteEvent::Choke { timing, .. }
+        | NoteEvent::VoiceTerminated { timing, .. }
+        | NoteEvent::PolyModulation { timing, .. }
+        | NoteEvent::MonoAutomation { timing, .. }
+        | NoteEvent::PolyPressure { timing, .. }
+        | NoteEvent::PolyVolume { timing, .. }
+        | NoteEvent::PolyPan { timing, .. }
+        | NoteEvent::PolyTuning { timing, .. }
+        | NoteEvent::PolyVibrato { timing, .. }
+        | NoteEvent::PolyExpression { timing, .. }
+        | NoteEvent::PolyBrightness { timing, .. }
+        | NoteEvent::MidiChannelPressure { timing, .. }
+        | NoteEvent::MidiPitchBend { timing, .. }
+        | NoteEvent::MidiCC { timing, .. }
+        | NoteEvent::MidiProgramChange { timing, .. }
+        | NoteEvent::MidiSysEx { timing, .. } => *timing = new_timing,
+        _ => (),
+    }
+}
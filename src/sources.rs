@@ -0,0 +1,194 @@
+// The input channels that are morphed between, and how much each of them counts.
+use std::sync::Arc;
+use nih_plug::prelude::*;
+use nih_plug::prelude::SmoothingStyle::Linear;
+
+/// The maximum number of input channels that can be morphed between.
+pub const MAX_SOURCES: usize = 16;
+
+/// Which channels are morphed between.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum SourceLayout {
+    /// Channel A and channel B, mixed by the interpolation amount.
+    #[name = "A/B"]
+    AB,
+    /// Every channel in the source list, mixed by its weight.
+    #[name = "Vector"]
+    Vector,
+    /// The first four sources in the corners of the XY pad, mixed by how close the pad is to them.
+    #[name = "XY Pad"]
+    XyPad,
+}
+
+/// What happens when some sources don't play in a group. The probabilistic mode only follows
+/// `Drop`: otherwise a source that didn't play can still be picked, and leaves the group silent.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum MissingPartner {
+    /// Leave them out, so a source that plays alone comes out unchanged.
+    #[name = "Pass Unchanged"]
+    Pass,
+    /// Don't play anything.
+    #[name = "Drop"]
+    Drop,
+    /// Interpolate against the notes they are still holding. Sources without any recent notes are
+    /// left out, like with `Pass`.
+    #[name = "Last Held"]
+    LastHeld,
+    /// Interpolate against the last chord they played, even if it already ended.
+    #[name = "Last Played"]
+    LastPlayed,
+}
+
+/// One entry of the source list.
+#[derive(Params)]
+pub struct SourceParams {
+    /// The input channel, 0 if this source isn't used.
+    #[id = "channel"]
+    pub channel: IntParam,
+
+    #[id = "weight"]
+    pub weight: FloatParam,
+
+    /// Send the notes of this source on their own channel too, besides the interpolated ones. In
+    /// the A/B layout, the first two sources stand for A and B.
+    #[id = "passthrough"]
+    pub passthrough: BoolParam,
+}
+
+impl SourceParams {
+    /// The parameters of the source at `index`. The first four sources listen to channels 1 to 4.
+    pub fn new(index: usize) -> Self {
+        let default_channel = if index < 4 { index as i32 + 1 } else { 0 };
+        Self {
+            channel: IntParam::new(
+                format!("Source {} Channel", index + 1),
+                default_channel,
+                IntRange::Linear { min: 0, max: 16 },
+            )
+                .with_value_to_string(Arc::new(|value| {
+                    if value == 0 { String::from("Off") } else { value.to_string() }
+                }))
+                .with_string_to_value(Arc::new(|string| {
+                    if string.trim().eq_ignore_ascii_case("off") {
+                        Some(0)
+                    } else {
+                        string.trim().parse().ok()
+                    }
+                })),
+
+            weight: FloatParam::new(
+                format!("Source {} Weight", index + 1),
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+                .with_smoother(Linear(50.0)),
+
+            passthrough: BoolParam::new(format!("Source {} Passthrough", index + 1), false),
+        }
+    }
+}
+
+/// The channels taking part in an interpolation, and their weights, which add up to 1.
+#[derive(Clone, Copy, Default)]
+pub struct Sources {
+    pub channels: [u8; MAX_SOURCES],
+    /// For everything that has no weights of its own, like onsets, lengths and controllers.
+    pub weights: [f32; MAX_SOURCES],
+    pub pitch_weights: [f32; MAX_SOURCES],
+    pub velocity_weights: [f32; MAX_SOURCES],
+    pub count: usize,
+    /// The sources whose own notes are sent as well, one bit per source.
+    pub passthrough: u16,
+}
+
+impl Sources {
+    /// Channel A and B, `interp` of the way from A to B.
+    pub fn a_b(chan_a: u8, chan_b: u8, interp: f32) -> Self {
+        let mut sources = Self::default();
+        sources.channels[..2].copy_from_slice(&[chan_a, chan_b]);
+        sources.weights[..2].copy_from_slice(&[1.0 - interp, interp]);
+        sources.pitch_weights = sources.weights;
+        sources.velocity_weights = sources.weights;
+        sources.count = 2;
+        sources
+    }
+
+    /// The same two channels, with pitch and velocity each moved by their own amount.
+    pub fn with_amounts(mut self, pitch: f32, velocity: f32) -> Self {
+        self.pitch_weights[..2].copy_from_slice(&[1.0 - pitch, pitch]);
+        self.velocity_weights[..2].copy_from_slice(&[1.0 - velocity, velocity]);
+        self
+    }
+
+    /// The same sources with their passthrough switches, in the order of the sources.
+    pub fn with_passthrough(mut self, switches: impl Iterator<Item = bool>) -> Self {
+        self.passthrough = switches
+            .take(self.count)
+            .enumerate()
+            .fold(0, |mask, (index, on)| if on { mask | 1 << index } else { mask });
+        self
+    }
+
+    /// Channels and their weights, in any scale. If all weights are zero, every channel counts
+    /// the same.
+    pub fn weighted(sources: impl Iterator<Item = (u8, f32)>) -> Self {
+        let mut result = Self::default();
+        for (channel, weight) in sources.take(MAX_SOURCES) {
+            result.channels[result.count] = channel;
+            result.weights[result.count] = weight.max(0.0);
+            result.count += 1;
+        }
+
+        let total: f32 = result.weights.iter().sum();
+        for weight in result.weights.iter_mut().take(result.count) {
+            *weight = if total > 0.0 { *weight / total } else { 1.0 / result.count as f32 };
+        }
+        result.pitch_weights = result.weights;
+        result.velocity_weights = result.weights;
+        result
+    }
+
+    /// The same sources with only the ones in `present` (one bit per source) taking part. Their
+    /// weights grow so they still add up to 1.
+    pub fn only(&self, present: u16) -> Self {
+        let mut result = *self;
+        let count = self.count;
+        let all = [&mut result.weights, &mut result.pitch_weights, &mut result.velocity_weights];
+        for weights in all {
+            let (mut total, mut members) = (0.0, 0);
+            for (index, weight) in weights.iter_mut().enumerate().take(count) {
+                if present & (1 << index) == 0 {
+                    *weight = 0.0;
+                } else {
+                    total += *weight;
+                    members += 1;
+                }
+            }
+            for (index, weight) in weights.iter_mut().enumerate().take(count) {
+                if present & (1 << index) != 0 {
+                    *weight = if total > 0.0 { *weight / total } else { 1.0 / members as f32 };
+                }
+            }
+        }
+        result
+    }
+
+    /// The source that listens to `channel`. If several do, the first one.
+    pub fn index_of(&self, channel: u8) -> Option<usize> {
+        self.channels[..self.count].iter().position(|&other| other == channel)
+    }
+
+    /// The source at the cumulative weight `position`, which is between 0 and 1. A random
+    /// `position` picks each source with the probability of its weight.
+    pub fn pick(&self, position: f32) -> usize {
+        let mut sum = 0.0;
+        for (index, weight) in self.weights[..self.count].iter().enumerate() {
+            sum += weight;
+            if position < sum {
+                return index;
+            }
+        }
+        // Rounding errors can leave the sum just short of 1
+        self.weights[..self.count].iter().rposition(|&weight| weight > 0.0).unwrap_or(0)
+    }
+}
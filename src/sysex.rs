@@ -0,0 +1,59 @@
+// The SysEx messages the plugin sends and passes on.
+use nih_plug::prelude::SysExMessage;
+
+/// The longest message that can be passed on. Longer ones are dropped, they couldn't be stored
+/// without allocating.
+const MAX_LENGTH: usize = 32;
+
+/// The length of an MTS single note tuning change for one key.
+const NOTE_TUNING_LENGTH: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SysEx {
+    /// A real-time MIDI Tuning Standard single note tuning change, making `key` sound at the
+    /// fractional MIDI note `pitch` on every channel.
+    NoteTuning { key: u8, pitch: f32 },
+    /// A message that came in, sent on as it is.
+    Other { data: [u8; MAX_LENGTH], length: usize },
+}
+
+impl SysExMessage for SysEx {
+    type Buffer = [u8; MAX_LENGTH];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        let mut data = [0; MAX_LENGTH];
+        data.get_mut(..buffer.len())?.copy_from_slice(buffer);
+        Some(SysEx::Other { data, length: buffer.len() })
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        match self {
+            SysEx::NoteTuning { key, pitch } => {
+                // The semitone below, and the rest in steps of 1/16384 semitone
+                let pitch = pitch.clamp(0.0, 127.0);
+                let semitone = pitch.floor();
+                let fraction = (((pitch - semitone) * 16384.0).round() as u16).min(16383);
+                let message: [u8; NOTE_TUNING_LENGTH] = [
+                    0xf0,
+                    0x7f,
+                    // all devices, tuning program 0, one key
+                    0x7f,
+                    0x08,
+                    0x02,
+                    0x00,
+                    0x01,
+                    key & 0x7f,
+                    semitone as u8,
+                    (fraction >> 7) as u8,
+                    (fraction & 0x7f) as u8,
+                    0xf7,
+                ];
+
+                let mut data = [0; MAX_LENGTH];
+                data[..NOTE_TUNING_LENGTH].copy_from_slice(&message);
+                (data, NOTE_TUNING_LENGTH)
+            }
+            SysEx::Other { data, length } => (data, length),
+        }
+    }
+}
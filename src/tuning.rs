@@ -0,0 +1,322 @@
+// Tunings loaded from Scala files: a scale (.scl) and an optional keyboard mapping (.kbm).
+// Parsing doesn't allocate, so it can happen on the audio thread.
+use crate::chord::Notes;
+use crate::scale::Rounding;
+
+/// The most notes a scale can have.
+const MAX_DEGREES: usize = 1024;
+
+/// The pitch of every MIDI key in a Scala tuning.
+#[derive(Clone, Copy)]
+pub struct Tuning {
+    /// As fractional MIDI notes in 12-TET with A4 at 440 Hz.
+    pitches: [f32; 128],
+    /// One bit for every key that plays a note of the scale.
+    mapped: u128,
+}
+
+impl Tuning {
+    /// Read the text of a .scl file and of a .kbm file. An empty mapping maps the scale linearly,
+    /// with degree 0 on middle C and A4 at 440 Hz.
+    pub fn parse(scale: &str, mapping: &str) -> Result<Self, &'static str> {
+        // The description may be empty, so only comments are skipped
+        let mut lines = scale.lines().map(str::trim).filter(|line| !line.starts_with('!'));
+        lines.next().ok_or("The scale file is empty")?;
+        let count: usize = lines
+            .next()
+            .and_then(first_word)
+            .and_then(|word| word.parse().ok())
+            .ok_or("The scale file has no number of notes")?;
+        if count == 0 || count > MAX_DEGREES {
+            return Err("The scale has to have between 1 and 1024 notes");
+        }
+
+        // In cents above degree 0, the last one being the period
+        let mut cents = [0.0f64; MAX_DEGREES + 1];
+        for step in cents.iter_mut().skip(1).take(count) {
+            *step = lines
+                .next()
+                .and_then(first_word)
+                .and_then(parse_cents)
+                .ok_or("A note of the scale can't be read")?;
+        }
+        let period = cents[count];
+        let degree_cents = |degree: i32| {
+            let octave = degree.div_euclid(count as i32);
+            octave as f64 * period + cents[degree.rem_euclid(count as i32) as usize]
+        };
+
+        let mapping = Mapping::parse(mapping, count)?;
+        let reference = mapping
+            .degree(mapping.reference_note)
+            .ok_or("The reference note isn't mapped")?;
+        // The pitch of degree 0
+        let base = 69.0 + 12.0 * (mapping.reference_frequency / 440.0).log2()
+            - degree_cents(reference) / 100.0;
+
+        let mut tuning = Self { pitches: [0.0; 128], mapped: 0 };
+        for key in mapping.first..=mapping.last {
+            if let Some(degree) = mapping.degree(key) {
+                tuning.pitches[key as usize] = (base + degree_cents(degree) / 100.0) as f32;
+                tuning.mapped |= 1u128 << key;
+            }
+        }
+        if tuning.mapped == 0 {
+            return Err("No key is mapped");
+        }
+        Ok(tuning)
+    }
+
+    /// The same tuning for a synth that has A4 at `reference` Hz.
+    pub fn with_reference(mut self, reference: f32) -> Self {
+        let shift = 12.0 * (440.0 / reference).log2();
+        for pitch in self.pitches.iter_mut() {
+            *pitch += shift;
+        }
+        self
+    }
+
+    /// Move a fractional MIDI pitch onto the closest note of the tuning that can still be played.
+    pub fn quantize(&self, pitch: f32, rounding: Rounding) -> f32 {
+        let (mut down, mut up): (Option<f32>, Option<f32>) = (None, None);
+        for key in Notes(self.mapped) {
+            let note = self.pitches[key as usize];
+            if !(0.0..=127.0).contains(&note) {
+                continue;
+            }
+            if note <= pitch && down.is_none_or(|down| note > down) {
+                down = Some(note);
+            }
+            if note >= pitch && up.is_none_or(|up| note < up) {
+                up = Some(note);
+            }
+        }
+
+        // Rather go the other way than leave the MIDI range
+        let (down, up) = match (down, up) {
+            (Some(down), Some(up)) => (down, up),
+            (Some(note), None) | (None, Some(note)) => (note, note),
+            (None, None) => return pitch.round().clamp(0.0, 127.0),
+        };
+        match rounding {
+            Rounding::Down => down,
+            Rounding::Up => up,
+            Rounding::Nearest => if pitch - down < up - pitch { down } else { up },
+        }
+    }
+}
+
+/// The description line of a .scl file.
+pub fn description(scale: &str) -> &str {
+    scale.lines().map(str::trim).find(|line| !line.starts_with('!')).unwrap_or("")
+}
+
+/// Which scale degree every key plays, from a .kbm file.
+struct Mapping {
+    /// How many keys the pattern repeats after, 0 for one degree per key.
+    size: i32,
+    first: u8,
+    last: u8,
+    /// The key that plays degree 0.
+    middle: i32,
+    reference_note: u8,
+    reference_frequency: f64,
+    /// How many degrees the pattern moves up every time it repeats.
+    octave: i32,
+    /// The degree of every key in the pattern, `None` for keys that don't play.
+    keys: [Option<i32>; 128],
+}
+
+impl Mapping {
+    fn parse(text: &str, count: usize) -> Result<Self, &'static str> {
+        let mut mapping = Self {
+            size: 0,
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave: count as i32,
+            keys: [None; 128],
+        };
+        let mut words = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('!'))
+            .filter_map(first_word);
+        if text.trim().is_empty() {
+            return Ok(mapping);
+        }
+
+        let mut number =
+            |error| words.next().and_then(|word| word.parse::<f64>().ok()).ok_or(error);
+        let size = number("The mapping has no size")?;
+        let first = number("The mapping has no first note")?;
+        let last = number("The mapping has no last note")?;
+        let middle = number("The mapping has no middle note")?;
+        let reference_note = number("The mapping has no reference note")?;
+        let reference_frequency = number("The mapping has no reference frequency")?;
+        let octave = number("The mapping has no octave degree")?;
+
+        let key = |value: f64| (0.0..=127.0).contains(&value).then_some(value as u8);
+        mapping.size = size as i32;
+        mapping.first = key(first).ok_or("The first note is out of range")?;
+        mapping.last = key(last).ok_or("The last note is out of range")?;
+        mapping.middle = middle as i32;
+        mapping.reference_note = key(reference_note).ok_or("The reference note is out of range")?;
+        mapping.reference_frequency = reference_frequency;
+        if !(0..=128).contains(&mapping.size) || reference_frequency <= 0.0 {
+            return Err("The mapping can't be read");
+        }
+        if octave > 0.0 {
+            mapping.octave = octave as i32;
+        }
+
+        // Keys that are left out at the end don't play
+        for entry in mapping.keys.iter_mut().take(mapping.size as usize) {
+            *entry = match words.next() {
+                Some("x") | Some("X") | None => None,
+                Some(word) => Some(word.parse().map_err(|_| "A key of the mapping can't be read")?),
+            };
+        }
+        Ok(mapping)
+    }
+
+    /// The scale degree `key` plays, counted from degree 0 on the middle note.
+    fn degree(&self, key: u8) -> Option<i32> {
+        let offset = key as i32 - self.middle;
+        if self.size == 0 {
+            return Some(offset);
+        }
+        let repeat = offset.div_euclid(self.size);
+        let degree = self.keys[offset.rem_euclid(self.size) as usize]?;
+        Some(degree + repeat * self.octave)
+    }
+}
+
+fn first_word(line: &str) -> Option<&str> {
+    line.split_whitespace().next()
+}
+
+/// A pitch of a .scl file in cents. Values with a period are cents already, the others are ratios.
+fn parse_cents(word: &str) -> Option<f64> {
+    if word.contains('.') {
+        return word.parse().ok();
+    }
+    let (numerator, denominator) = word.split_once('/').unwrap_or((word, "1"));
+    let ratio = numerator.parse::<u64>().ok()? as f64 / denominator.parse::<u64>().ok()? as f64;
+    (ratio > 0.0 && ratio.is_finite()).then(|| 1200.0 * ratio.log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWELVE_TET: &str = "! 12tet.scl
+!
+12-TET
+ 12
+!
+ 100.0
+ 200.0
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    fn assert_pitch(tuning: &Tuning, key: u8, pitch: f32) {
+        assert!(tuning.mapped & (1u128 << key) != 0, "key {key} isn't mapped");
+        let actual = tuning.pitches[key as usize];
+        assert!((actual - pitch).abs() < 1e-3, "key {key} is {actual}, not {pitch}");
+    }
+
+    #[test]
+    fn ratios_and_cents_can_be_mixed() {
+        let scale = "Pythagorean fifth and whole tone\n3\n9/8\n701.955\n2\n";
+        let tuning = Tuning::parse(scale, "").unwrap();
+        // Without a mapping, degree 0 is on middle C and A4 is at 440 Hz
+        assert_pitch(&tuning, 69, 69.0);
+        assert_pitch(&tuning, 70, 69.0 + 2.03910);
+        assert_pitch(&tuning, 71, 69.0 + 7.01955);
+        assert_pitch(&tuning, 72, 81.0);
+        // Nine keys below A4 are three periods of the scale
+        assert_pitch(&tuning, 60, 33.0);
+    }
+
+    #[test]
+    fn keys_marked_x_or_left_out_are_unmapped() {
+        // Size 12 over keys 0 to 127, degree 0 on key 60, A4 (key 69) at 440 Hz, 12 degrees per
+        // repeat. Key 1 plays nothing, the last key isn't listed.
+        let mapping = "! test.kbm
+12
+0
+127
+60
+69
+440.0
+12
+0
+x
+2
+3
+4
+5
+6
+7
+8
+9
+10
+";
+        let tuning = Tuning::parse(TWELVE_TET, mapping).unwrap();
+        for key in [60, 62, 69, 70, 72, 74] {
+            assert_pitch(&tuning, key, key as f32);
+        }
+        for key in [61, 71, 73, 83] {
+            assert!(tuning.mapped & (1u128 << key) == 0, "key {key} is mapped");
+        }
+        // The unmapped key is skipped when snapping
+        assert_eq!(tuning.quantize(61.2, Rounding::Nearest), 62.0);
+        assert_eq!(tuning.quantize(61.2, Rounding::Down), 60.0);
+    }
+
+    #[test]
+    fn the_reference_note_sets_the_tuning() {
+        let mapping = "0\n0\n127\n60\n69\n432.0\n0\n";
+        let tuning = Tuning::parse(TWELVE_TET, mapping).unwrap();
+        let shift = 12.0 * (432.0f32 / 440.0).log2();
+        assert_pitch(&tuning, 69, 69.0 + shift);
+        assert_pitch(&tuning, 60, 60.0 + shift);
+
+        // Middle C as the reference, at its usual frequency
+        let mapping = "0\n0\n127\n60\n60\n261.625565\n0\n";
+        let tuning = Tuning::parse(TWELVE_TET, mapping).unwrap();
+        assert_pitch(&tuning, 60, 60.0);
+        assert_pitch(&tuning, 69, 69.0);
+
+        // A synth tuned to A4 = 432 Hz needs its keys a bit higher for the same frequencies
+        assert_pitch(&tuning.with_reference(432.0), 69, 69.0 - shift);
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        assert!(Tuning::parse("", "").is_err());
+        assert!(Tuning::parse("No count", "").is_err());
+        assert!(Tuning::parse("Empty\n0\n", "").is_err());
+        assert!(Tuning::parse("Too short\n3\n100.0\n200.0\n", "").is_err());
+        assert!(Tuning::parse("Not a pitch\n1\nfifth\n", "").is_err());
+        assert!(Tuning::parse("Division by zero\n1\n3/0\n", "").is_err());
+
+        // Missing header values, a key out of range, an unreadable key and an unmapped reference
+        assert!(Tuning::parse(TWELVE_TET, "12\n0\n127\n").is_err());
+        assert!(Tuning::parse(TWELVE_TET, "0\n0\n200\n60\n69\n440.0\n0\n").is_err());
+        assert!(Tuning::parse(TWELVE_TET, "1\n0\n127\n60\n69\n440.0\n12\nfoo\n").is_err());
+        assert!(Tuning::parse(TWELVE_TET, "1\n0\n127\n60\n69\n440.0\n12\nx\n").is_err());
+    }
+}
@@ -0,0 +1,397 @@
+// Keeps track of the interpolated notes that are currently sounding and of the input notes that
+// produced them, so the NoteOffs of the inputs can be turned into NoteOffs for the right output.
+use crate::chord::Notes;
+use crate::sources::MAX_SOURCES;
+
+/// The maximum number of interpolated notes that can be held at the same time. Every voice gets one
+/// bit in a `u128`.
+pub const MAX_VOICES: usize = 128;
+
+/// How many inputs can take part in one voice.
+const MAX_SIDES: usize = MAX_SOURCES;
+
+/// The notes of one input that produced a voice.
+#[derive(Clone, Copy, Default)]
+struct Side {
+    present: bool,
+    channel: u8,
+    /// The absolute sample the input notes started at.
+    onset: u64,
+    /// How much the length of this input counts towards the length of the voice.
+    weight: f32,
+    /// The input notes, one bit per MIDI note.
+    notes: u128,
+    /// How many of the notes are still held.
+    held: u8,
+    /// When the last of the notes ended.
+    end: Option<u64>,
+}
+
+/// What a voice plays and which input notes produced it.
+pub struct VoiceInfo {
+    pub channel: u8,
+    pub note: u8,
+    pub id: Option<i32>,
+    /// The channel and notes (one bit per MIDI note) of every input that took part. Unused entries
+    /// have no notes.
+    pub inputs: [(u8, u128); MAX_SIDES],
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    channel: u8,
+    note: u8,
+    /// Voices with an ID are stopped on their own, even if other voices share their note.
+    id: Option<i32>,
+    /// The absolute sample the NoteOn is sent at.
+    onset: u64,
+    /// When the voice has to stop at the latest, once some of its inputs have ended.
+    deadline: Option<u64>,
+    sides: [Side; MAX_SIDES],
+}
+
+impl Voice {
+    /// The length of the voice in samples, interpolated between the lengths of its inputs. Inputs
+    /// that are still held count as if they ended at `now`.
+    fn length(&self, now: u64) -> u64 {
+        let (mut sum, mut weights, mut plain_sum, mut count) = (0.0, 0.0, 0.0, 0.0);
+        for side in self.sides.iter().filter(|side| side.present) {
+            let length = side.end.unwrap_or(now).saturating_sub(side.onset) as f64;
+            sum += side.weight as f64 * length;
+            weights += side.weight as f64;
+            plain_sum += length;
+            count += 1.0;
+        }
+
+        let length = if weights > 0.0 {
+            sum / weights
+        } else if count > 0.0 {
+            plain_sum / count
+        } else {
+            0.0
+        };
+        length.round() as u64
+    }
+
+    /// The sample at which the voice would have to stop if the inputs that are still held went
+    /// on, but not before `time`. Held inputs make the length grow slower than time, this is
+    /// where the two meet. `None` as long as the ended inputs don't count towards the length.
+    fn deadline(&self, time: u64) -> Option<u64> {
+        let weighted = self.sides.iter().any(|side| side.present && side.weight > 0.0);
+        let (mut total, mut held, mut ended_lengths, mut held_onsets) = (0.0, 0.0, 0.0, 0.0);
+        for side in self.sides.iter().filter(|side| side.present) {
+            let weight = if weighted { side.weight as f64 } else { 1.0 };
+            total += weight;
+            match side.end {
+                Some(end) => ended_lengths += weight * end.saturating_sub(side.onset) as f64,
+                None => {
+                    held += weight;
+                    held_onsets += weight * side.onset as f64;
+                }
+            }
+        }
+        if held >= total {
+            return None;
+        }
+
+        let deadline =
+            (self.onset as f64 + (ended_lengths - held_onsets) / total) / (1.0 - held / total);
+        Some((deadline.round().max(0.0) as u64).max(time))
+    }
+}
+
+pub struct VoiceTable {
+    voices: [Option<Voice>; MAX_VOICES],
+    /// For every input channel and note, one bit for each voice that this input note produced.
+    sources: [[u128; 128]; 16],
+    /// How many voices are sounding on each output channel and note. Several voices can end up on
+    /// the same pitch, only the last one to stop sends a NoteOff.
+    sounding: [[u8; 128]; 16],
+    /// Where the search for a free channel starts next, relative to the start of the pool.
+    next_channel: u8,
+    /// The ID the next voice that asks for one gets.
+    next_id: i32,
+}
+
+impl Default for VoiceTable {
+    fn default() -> Self {
+        Self {
+            voices: [None; MAX_VOICES],
+            sources: [[0; 128]; 16],
+            sounding: [[0; 128]; 16],
+            next_channel: 0,
+            next_id: 0,
+        }
+    }
+}
+
+impl VoiceTable {
+    /// Register a new output note that starts at the absolute sample `onset`, optionally with a
+    /// voice ID of its own. Returns the index of its voice, or `None` if all voices are taken, in
+    /// which case the note should not be sent at all.
+    pub fn start_voice(
+        &mut self,
+        channel: u8,
+        note: u8,
+        with_id: bool,
+        onset: u64,
+    ) -> Option<usize> {
+        let index = self.voices.iter().position(|voice| voice.is_none())?;
+        let id = with_id.then(|| {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1) & i32::MAX;
+            id
+        });
+        self.voices[index] = Some(Voice {
+            channel,
+            note,
+            id,
+            onset,
+            deadline: None,
+            sides: [Side::default(); MAX_SIDES],
+        });
+
+        let sounding = &mut self.sounding[channel as usize][note as usize];
+        *sounding = sounding.saturating_add(1);
+
+        Some(index)
+    }
+
+    /// The voice ID of a sounding voice, if it has one.
+    pub fn id(&self, voice: usize) -> Option<i32> {
+        self.voices[voice].and_then(|voice| voice.id)
+    }
+
+    /// Call `f` for every voice that the input note on `channel` is part of.
+    pub fn voices_of(&self, channel: u8, note: u8, mut f: impl FnMut(&VoiceInfo)) {
+        let voices = self.sources[channel as usize][note as usize];
+        for (index, voice) in self.voices.iter().enumerate() {
+            let Some(voice) = voice else {
+                continue;
+            };
+            if voices & (1u128 << index) == 0 {
+                continue;
+            }
+
+            let mut inputs = [(0, 0); MAX_SIDES];
+            for (input, side) in inputs.iter_mut().zip(voice.sides.iter()) {
+                if side.present {
+                    *input = (side.channel, side.notes);
+                }
+            }
+            f(&VoiceInfo {
+                channel: voice.channel,
+                note: voice.note,
+                id: voice.id,
+                inputs,
+            });
+        }
+    }
+
+    /// Forget every voice, without stopping them.
+    pub fn clear(&mut self) {
+        self.voices.fill(None);
+        self.sources.iter_mut().for_each(|notes| notes.fill(0));
+        self.sounding.iter_mut().for_each(|notes| notes.fill(0));
+    }
+
+    /// The key closest to `near` that has nothing sounding on `channel`, or `near` itself if every
+    /// key is busy.
+    pub fn free_key(&self, channel: u8, near: u8) -> u8 {
+        for distance in 0..128 {
+            for key in [near as i32 + distance, near as i32 - distance] {
+                if (0..128).contains(&key) && self.sounding[channel as usize][key as usize] == 0 {
+                    return key as u8;
+                }
+            }
+        }
+        near
+    }
+
+    /// Pick one of `count` channels starting at `first` for a new voice. Channels are used round
+    /// robin, skipping the ones that still have notes sounding. If all of them are busy, the voice
+    /// has to share a channel.
+    pub fn allocate_channel(&mut self, first: u8, count: u8) -> u8 {
+        let count = count.max(1);
+        for i in 0..count {
+            let channel = first + (self.next_channel + i) % count;
+            if self.sounding[channel as usize].iter().all(|&sounding| sounding == 0) {
+                self.next_channel = (self.next_channel + i + 1) % count;
+                return channel;
+            }
+        }
+
+        let channel = first + self.next_channel % count;
+        self.next_channel = (self.next_channel + 1) % count;
+        channel
+    }
+
+    /// Link the input notes on `channel` (one bit per MIDI note) to a voice. They started at the
+    /// absolute sample `onset`, and `weight` says how much their length counts towards the
+    /// length of the voice.
+    pub fn add_sources(&mut self, voice: usize, channel: u8, notes: u128, onset: u64, weight: f32) {
+        if notes == 0 {
+            return;
+        }
+        let Some(sides) = self.voices[voice].as_mut().map(|voice| &mut voice.sides) else {
+            return;
+        };
+        if let Some(side) = sides.iter_mut().find(|side| !side.present) {
+            *side = Side {
+                present: true,
+                channel,
+                onset,
+                weight,
+                notes,
+                held: notes.count_ones() as u8,
+                end: None,
+            };
+        }
+
+        for note in Notes(notes) {
+            self.sources[channel as usize][note as usize] |= 1u128 << voice;
+        }
+    }
+
+    /// Handle the NoteOff of an input note at the absolute sample `time`. Once all inputs of a
+    /// voice have ended, its interpolated length is known and `note_off` is called with the
+    /// channel, note, voice ID and the time the output note should stop, if it is no longer held.
+    pub fn release_source(
+        &mut self,
+        channel: u8,
+        note: u8,
+        time: u64,
+        mut note_off: impl FnMut(u8, u8, Option<i32>, u64),
+    ) {
+        let mut voices = std::mem::take(&mut self.sources[channel as usize][note as usize]);
+
+        while voices != 0 {
+            let index = voices.trailing_zeros() as usize;
+            voices &= voices - 1;
+
+            let Some(voice) = self.voices[index].as_mut() else {
+                continue;
+            };
+            let side = voice
+                .sides
+                .iter_mut()
+                .find(|side| side.present && side.channel == channel && side.held > 0);
+            if let Some(side) = side {
+                side.held -= 1;
+                if side.held == 0 {
+                    side.end = Some(time);
+                }
+            }
+
+            if voice.sides.iter().all(|side| !side.present || side.end.is_some()) {
+                // Inputs that ended after the deadline can't make the voice any longer
+                let end = voice.onset + voice.length(time);
+                let end = voice.deadline.map_or(end, |deadline| end.min(deadline));
+                self.stop_voice(index, end, &mut note_off);
+            } else {
+                voice.deadline = voice.deadline(time);
+            }
+        }
+    }
+
+    /// Stop the voices that are still waiting for some of their inputs to end, but whose deadline
+    /// is before `now`, at their deadline. Without latency the deadline is where the first input
+    /// ended, so the length only follows the later inputs for as long as the output is delayed.
+    pub fn release_overdue(
+        &mut self,
+        now: u64,
+        mut note_off: impl FnMut(u8, u8, Option<i32>, u64),
+    ) {
+        let mut overdue = 0u128;
+        for (index, voice) in self.voices.iter().enumerate() {
+            if voice.and_then(|voice| voice.deadline).is_some_and(|deadline| deadline < now) {
+                overdue |= 1u128 << index;
+            }
+        }
+
+        while overdue != 0 {
+            let index = overdue.trailing_zeros() as usize;
+            overdue &= overdue - 1;
+            let end = self.voices[index].and_then(|voice| voice.deadline).unwrap_or(now);
+            self.stop_voice(index, end, &mut note_off);
+        }
+    }
+
+    fn stop_voice(
+        &mut self,
+        index: usize,
+        time: u64,
+        note_off: &mut impl FnMut(u8, u8, Option<i32>, u64),
+    ) {
+        let Some(voice) = self.voices[index].take() else {
+            return;
+        };
+
+        // The other input notes of this voice must not stop whatever ends up in this slot next
+        for notes in self.sources.iter_mut() {
+            for voices in notes.iter_mut() {
+                *voices &= !(1u128 << index);
+            }
+        }
+
+        let sounding = &mut self.sounding[voice.channel as usize][voice.note as usize];
+        *sounding = sounding.saturating_sub(1);
+        if *sounding == 0 || voice.id.is_some() {
+            note_off(voice.channel, voice.note, voice.id, time.max(voice.onset));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A voice of an A and a B note on channels 0 and 1 that both started at 0, sent `latency`
+    /// samples later, with B weighted by `interp`.
+    fn voice(table: &mut VoiceTable, latency: u64, interp: f32) {
+        let voice = table.start_voice(0, 60, false, latency).unwrap();
+        table.add_sources(voice, 0, 1u128 << 60, 0, 1.0 - interp);
+        table.add_sources(voice, 1, 1u128 << 64, 0, interp);
+    }
+
+    /// When the voice stops if A ends at `end_a`, with B still held, and the overdue voices are
+    /// stopped at the end of every block of `block` samples.
+    fn stop_time(latency: u64, end_a: u64, block: u64) -> Option<u64> {
+        let mut table = VoiceTable::default();
+        voice(&mut table, latency, 0.5);
+        let mut stopped = None;
+        table.release_source(0, 60, end_a, |_, _, _, time| stopped = Some(time));
+        let mut now = 0;
+        while stopped.is_none() && now < 10_000 {
+            now += block;
+            table.release_overdue(now, |_, _, _, time| stopped = Some(time));
+        }
+        stopped
+    }
+
+    #[test]
+    fn overdue_voices_stop_at_the_same_time_for_every_block_size() {
+        for latency in [0, 50] {
+            let times = [1, 64, 128, 256].map(|block| stop_time(latency, 100, block));
+            assert!(times.iter().all(|&time| time == times[0]), "{times:?}");
+        }
+        // Without latency the voice stops where A ended
+        assert_eq!(stop_time(0, 100, 128), Some(100));
+        // The latency lets the length follow B for a while, until the two meet
+        assert_eq!(stop_time(50, 100, 128), Some(200));
+    }
+
+    #[test]
+    fn inputs_that_end_in_time_set_the_length() {
+        let mut table = VoiceTable::default();
+        voice(&mut table, 50, 0.5);
+        let mut stopped = None;
+        table.release_source(0, 60, 100, |_, _, _, time| stopped = Some(time));
+        table.release_overdue(128, |_, _, _, time| stopped = Some(time));
+        assert_eq!(stopped, None);
+        // B ends before the deadline, so the voice is as long as the mean of both
+        table.release_source(1, 64, 150, |_, _, _, time| stopped = Some(time));
+        assert_eq!(stopped, Some(50 + 125));
+    }
+}